@@ -0,0 +1,2370 @@
+//! Evict downloaded iCloud files with `brctl evict` while keeping them in the cloud.
+//!
+//! [`run`] walks the targets of an [`EvictOptions`], filters the files it finds and evicts
+//! the rest, returning an [`EvictReport`]. Diagnostics go through the `log` crate.
+//! [`run_with`] evicts through any [`Evictor`] instead of `brctl` and reports every file
+//! to an [`Observer`] as it is processed.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, Once};
+use std::time::{Duration, Instant, SystemTime};
+
+use globset::GlobSet;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::{debug, error, info, log, trace, warn, Level};
+use rayon::prelude::*;
+use regex::Regex;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+#[cfg(all(feature = "native", target_os = "macos"))]
+mod native;
+#[cfg(all(feature = "native", target_os = "macos"))]
+pub use native::NativeEvictor;
+
+/// What to evict and how.
+#[derive(Clone, Debug)]
+pub struct EvictOptions {
+    /// Directories to walk; a file evicts just that file
+    pub directories: Vec<PathBuf>,
+    /// Evict exactly these files, numbered by their line in the list, instead of walking
+    /// `directories`; the walk filters don't apply to them
+    pub file_list: Option<Vec<(usize, PathBuf)>>,
+    /// Count listed files that no longer exist as skipped instead of failed
+    pub skip_missing: bool,
+    /// Maximum number of `brctl` calls running at once
+    pub concurrency: usize,
+    /// Report what would be evicted without calling `brctl`
+    pub dry_run: bool,
+    /// Number of files passed to each `brctl evict` call
+    pub batch_size: usize,
+    /// Follow symbolic links instead of skipping them
+    pub follow_symlinks: bool,
+    /// Skip dotfiles and dot-directories
+    pub skip_hidden: bool,
+    /// Descend at most this many levels below each directory
+    pub max_depth: Option<usize>,
+    /// Only evict files at least this many levels below each directory
+    pub min_depth: Option<usize>,
+    /// Never descend into directories with these names, or at these paths relative to
+    /// the target directory when they contain a `/` (e.g. `photos/raw` or `./build`)
+    pub prune_dir: Vec<OsString>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Only evict files whose relative path matches one of these, if any are given
+    pub include: GlobSet,
+    /// Never evict files whose relative path matches one of these
+    pub exclude: GlobSet,
+    /// Only evict files whose relative path matches one of these, if any are given
+    pub regex: Vec<Regex>,
+    /// Match `regex` against the absolute path instead
+    pub regex_full_path: bool,
+    /// Only evict files with one of these lowercase extensions, if any are given
+    pub ext: Vec<String>,
+    /// Never evict files with one of these lowercase extensions
+    pub exclude_ext: Vec<String>,
+    /// Also pass `.<name>.icloud` placeholders to `brctl`
+    pub include_evicted: bool,
+    /// Also pass zero-byte files to `brctl`, which have no space to give back
+    pub include_empty: bool,
+    /// Only evict files last modified longer ago than this
+    pub older_than: Option<Duration>,
+    /// Never evict files modified within this window; zero turns it off
+    pub keep_recent: Duration,
+    /// Only evict files last used before this point
+    pub accessed_before: Option<SystemTime>,
+    /// Count walk errors without logging them
+    pub ignore_walk_errors: bool,
+    /// Don't read `.evictignore`, `.icloudignore` or `.gitignore` files
+    pub no_ignore: bool,
+    /// Stop once this many bytes have been reclaimed, evicting the largest files first
+    pub free: Option<u64>,
+    /// Kill a `brctl` call running longer than this per file
+    pub timeout: Option<Duration>,
+    /// Retry a failed eviction this many more times, unless the error is permanent
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after it
+    pub retry_delay: Duration,
+    /// Pause each worker this long after every eviction
+    pub delay: Duration,
+    /// Order to evict in; defaults to largest first with `free`, and to walk order otherwise
+    pub sort: Option<SortOrder>,
+    /// Only attempt this many of the largest files
+    pub top: Option<usize>,
+    /// Evict files while the walk is still finding them instead of collecting them all
+    /// first; `sort` and `top` need the whole list, so they are ignored
+    pub stream: bool,
+    /// Evict each target with a single `brctl evict <directory>` call that recurses by
+    /// itself; the walk only counts the files for the report, so `brctl` evicts whatever
+    /// the filters and protections (`keep_recent`, ignore files, open files) left out
+    /// too. Takes precedence over `stream`
+    pub recursive_evict: bool,
+    /// Cap on evictor calls (`brctl` processes) running at once, whatever `concurrency` is
+    pub max_inflight: Option<usize>,
+    /// Evict at most this many files per second across all workers
+    pub throttle: Option<f64>,
+    /// How many levels below each target directory [`EvictReport::by_directory`] groups by
+    pub by_dir_depth: usize,
+    /// Evict files even while another process has them open
+    pub evict_open_files: bool,
+    /// A later pass over targets already run once, e.g. a rescan while watching; finding
+    /// nothing to evict is then only logged at debug level
+    pub rescan: bool,
+    /// Evict files outside iCloud Drive; without it, targets and listed files outside it
+    /// are left alone, see [`is_icloud_path`]
+    pub allow_non_icloud: bool,
+    /// Attempt eviction even for files that already look evicted
+    pub force: bool,
+    /// Trust `brctl`'s exit status instead of checking that evicted files are really gone
+    pub no_verify: bool,
+    /// Take every file for downloaded instead of checking it, saving a `stat` per file;
+    /// files that are already evicted are then selected too
+    pub assume_downloaded: bool,
+    /// Units of the sizes in log messages
+    pub units: Units,
+    /// Set from another thread (e.g. a Ctrl+C handler) to stop after the batches in flight
+    pub stop: Arc<AtomicBool>,
+}
+
+impl Default for EvictOptions {
+    fn default() -> Self {
+        Self {
+            directories: Vec::new(),
+            file_list: None,
+            skip_missing: false,
+            concurrency: num_cpus::get(),
+            dry_run: false,
+            batch_size: 64,
+            follow_symlinks: false,
+            skip_hidden: false,
+            max_depth: None,
+            min_depth: None,
+            prune_dir: Vec::new(),
+            min_size: None,
+            max_size: None,
+            include: GlobSet::empty(),
+            exclude: GlobSet::empty(),
+            regex: Vec::new(),
+            regex_full_path: false,
+            ext: Vec::new(),
+            exclude_ext: Vec::new(),
+            include_evicted: false,
+            include_empty: false,
+            older_than: None,
+            keep_recent: Duration::from_secs(24 * 60 * 60),
+            accessed_before: None,
+            ignore_walk_errors: false,
+            no_ignore: false,
+            free: None,
+            timeout: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            delay: Duration::ZERO,
+            sort: None,
+            top: None,
+            stream: false,
+            recursive_evict: false,
+            max_inflight: None,
+            throttle: None,
+            by_dir_depth: 1,
+            evict_open_files: false,
+            rescan: false,
+            allow_non_icloud: false,
+            force: false,
+            no_verify: false,
+            assume_downloaded: false,
+            units: Units::Binary,
+            stop: Arc::default(),
+        }
+    }
+}
+
+/// Order in which the selected files are evicted.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Largest files first
+    SizeDesc,
+    /// Smallest files first
+    SizeAsc,
+    /// Alphabetically by path
+    Path,
+    /// Least recently modified first
+    MtimeAsc,
+    /// Most recently modified first
+    MtimeDesc,
+    /// Least recently used (read or written) first
+    AtimeAsc,
+    /// Most recently used first
+    AtimeDesc,
+}
+
+/// Hooks for following a run as it happens; every method defaults to doing nothing.
+pub trait Observer: Sync {
+    /// Called once the files to evict are known, before any of them is touched; returning
+    /// `false` cancels the run.
+    fn starting(&self, _files: usize, _bytes: u64) -> bool {
+        true
+    }
+
+    /// Called instead of [`Observer::starting`] by a [`EvictOptions::stream`] run, before
+    /// the walk begins; returning `false` cancels the run.
+    fn streaming(&self) -> bool {
+        true
+    }
+
+    /// Called by a [`EvictOptions::stream`] run once the walk is over and the selected
+    /// files are known, while the last of them may still be evicted.
+    fn walk_done(&self, _files: usize, _bytes: u64) {}
+
+    /// Asked about every selected file right before it is evicted; the default evicts
+    /// them all.
+    fn decide(&self, _path: &Path, _bytes: u64) -> Decision {
+        Decision::Evict
+    }
+
+    /// Called for every file that was evicted, failed, or was skipped once selected.
+    fn file_done(&self, _event: &FileEvent) {}
+}
+
+/// What [`Observer::decide`] wants done with a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Evict,
+    /// Leave this file alone, counted as skipped
+    Skip,
+    /// Leave this and all remaining files alone, without marking the run as interrupted
+    Stop,
+}
+
+impl Observer for () {}
+
+/// What happened to a single file.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Success,
+    Failed,
+    Skipped,
+    WouldEvict,
+    /// `brctl` succeeded but the file is still downloaded
+    NoOp,
+}
+
+impl Outcome {
+    /// The name used in logs and serialized output, e.g. `would-evict`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+            Outcome::WouldEvict => "would-evict",
+            Outcome::NoOp => "no-op",
+        }
+    }
+}
+
+/// One processed file, as passed to [`Observer::file_done`].
+#[derive(Debug)]
+pub struct FileEvent<'a> {
+    pub path: &'a Path,
+    pub size_bytes: u64,
+    pub outcome: Outcome,
+    /// Exit code of `brctl`, when it ran and exited normally
+    pub exit_code: Option<i32>,
+    pub error: Option<&'a str>,
+    /// How long the `brctl` call took; batched files share the batch's duration
+    pub duration: Option<Duration>,
+}
+
+/// A file that could not be evicted, with the reason.
+#[derive(Serialize, Debug)]
+pub struct Failure {
+    pub path: String,
+    pub error: String,
+    /// `error` without what is particular to the file, such as its path, its line in the
+    /// file list or the number of attempts, so that failures can be grouped by it
+    pub reason: String,
+}
+
+/// `error` with mentions of `path` replaced, e.g. in `brctl`'s messages.
+fn failure_reason(path: &Path, error: &str) -> String {
+    let path = path.display().to_string();
+    if path.is_empty() {
+        return error.to_string();
+    }
+    error.replace(&path, "<file>")
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Count {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl std::ops::AddAssign for Count {
+    fn add_assign(&mut self, other: Count) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DirectoryTotals {
+    pub directory: String,
+    pub successful: Count,
+    pub failed: Count,
+}
+
+/// Files evicted below one subdirectory of a target directory.
+#[derive(Serialize, Debug)]
+pub struct SubdirectoryTotals {
+    /// Relative to the target directory, or `.` for files directly inside it; prefixed
+    /// with the target directory when there were several
+    pub directory: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Files evicted with one extension.
+#[derive(Serialize, Debug)]
+pub struct ExtensionTotals {
+    /// Lowercase extension, or `(none)`
+    pub extension: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SkippedFiles {
+    pub reason: &'static str,
+    #[serde(skip)]
+    pub description: &'static str,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SkippedDirs {
+    pub reason: &'static str,
+    #[serde(skip)]
+    pub description: &'static str,
+    pub directories: usize,
+}
+
+/// Outcome of a [`run`].
+#[derive(Serialize, Debug)]
+pub struct EvictReport {
+    /// The directories that were walked, after dropping duplicates and nested ones
+    #[serde(skip)]
+    pub directories: Vec<PathBuf>,
+    /// The run was stopped through [`EvictOptions::stop`]
+    pub interrupted: bool,
+    /// [`Observer::starting`] declined, so nothing was attempted
+    #[serde(skip)]
+    pub cancelled: bool,
+    /// Files that passed the filters
+    #[serde(skip)]
+    pub selected: Count,
+    pub attempted: Count,
+    pub successful: Count,
+    pub failed: Count,
+    /// Failed files whose last attempt ran into the timeout
+    pub timed_out: Count,
+    /// `brctl` succeeded but the files were still downloaded afterwards
+    pub no_op: Count,
+    /// Successful files that needed at least one retry
+    pub retried: Count,
+    /// Selected files left alone because the run was stopped or met its `free` target
+    pub unattempted: Count,
+    /// Totals per directory, when there were several
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub per_directory: Vec<DirectoryTotals>,
+    /// Evicted files per subdirectory of the target directories, most bytes first
+    pub by_directory: Vec<SubdirectoryTotals>,
+    /// Evicted files per extension, most bytes first
+    pub by_extension: Vec<ExtensionTotals>,
+    pub free_target_bytes: Option<u64>,
+    pub free_target_met: Option<bool>,
+    pub skipped: Vec<SkippedFiles>,
+    pub skipped_directories: Vec<SkippedDirs>,
+    /// Unreadable entries and symlink loops; whatever lies below them is never seen
+    pub walk_errors: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl EvictReport {
+    /// Add the results of a later run over the same directories, as in a watch loop.
+    ///
+    /// Evictions and failures accumulate, while the skipped files and walk errors are
+    /// those of the latest run, since every run walks the same files again.
+    pub fn merge(&mut self, later: EvictReport) {
+        self.interrupted |= later.interrupted;
+        self.selected += later.selected;
+        self.attempted += later.attempted;
+        self.successful += later.successful;
+        self.failed += later.failed;
+        self.no_op += later.no_op;
+        self.timed_out += later.timed_out;
+        self.retried += later.retried;
+        self.unattempted += later.unattempted;
+        for (totals, later) in self.per_directory.iter_mut().zip(later.per_directory) {
+            totals.successful += later.successful;
+            totals.failed += later.failed;
+        }
+        for later in later.by_directory {
+            match self.by_directory.iter_mut().find(|totals| totals.directory == later.directory) {
+                Some(totals) => {
+                    totals.files += later.files;
+                    totals.bytes += later.bytes;
+                }
+                None => self.by_directory.push(later),
+            }
+        }
+        self.by_directory
+            .sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.directory.cmp(&b.directory)));
+        for later in later.by_extension {
+            match self.by_extension.iter_mut().find(|totals| totals.extension == later.extension) {
+                Some(totals) => {
+                    totals.files += later.files;
+                    totals.bytes += later.bytes;
+                }
+                None => self.by_extension.push(later),
+            }
+        }
+        self.by_extension
+            .sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.extension.cmp(&b.extension)));
+        self.free_target_met = self.free_target_bytes.map(|target| self.successful.bytes >= target);
+        self.skipped = later.skipped;
+        self.skipped_directories = later.skipped_directories;
+        self.walk_errors = later.walk_errors;
+        self.failures.extend(later.failures);
+    }
+}
+
+/// Count a skipped file and list it at `-vv`.
+fn skip(tally: &Tally, path: &Path, size: u64, reason: &str) {
+    tally.add(size);
+    debug!("Skipping {} ({})", path.display(), reason);
+}
+
+/// Count a skipped directory and list it at `-vv`.
+fn skip_dir(count: &AtomicUsize, path: &Path, reason: &str) {
+    count.fetch_add(1, Ordering::Relaxed);
+    debug!("Skipping directory {} ({})", path.display(), reason);
+}
+
+/// Running counts of the files attempted, evicted and failed, shared by all workers.
+#[derive(Default)]
+struct Stats {
+    attempted: AtomicUsize,
+    successful: AtomicUsize,
+    failed: AtomicUsize,
+    attempted_bytes: AtomicU64,
+    successful_bytes: AtomicU64,
+    failed_bytes: AtomicU64,
+    no_op: Tally,
+    timed_out: Tally,
+    retried: Tally,
+    unattempted: Tally,
+    /// Evicted files by subdirectory, see [`subdirectory_key`]
+    by_directory: Mutex<HashMap<String, Count>>,
+    /// Evicted files by lowercase extension
+    by_extension: Mutex<HashMap<String, Count>>,
+}
+
+/// Add a file of `bytes` to the totals of `key`.
+fn add_to(totals: &Mutex<HashMap<String, Count>>, key: String, bytes: u64) {
+    let mut totals = totals.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let count = totals.entry(key).or_default();
+    count.files += 1;
+    count.bytes += bytes;
+}
+
+/// The totals in `totals` as `(key, count)`, most bytes first.
+fn sorted_totals(totals: &Mutex<HashMap<String, Count>>) -> Vec<(String, Count)> {
+    let totals = totals.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut sorted: Vec<(String, Count)> = totals.iter().map(|(key, count)| (key.clone(), *count)).collect();
+    sorted.sort_by(|(a, a_count), (b, b_count)| b_count.bytes.cmp(&a_count.bytes).then_with(|| a.cmp(b)));
+    sorted
+}
+
+/// The subdirectory `depth` levels below `root` that `path` lies in, or `.` when it is
+/// directly inside `root`; `root` itself goes in front when `prefix_root` is set.
+fn subdirectory_key(root: &Path, path: &Path, depth: usize, prefix_root: bool) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let parent = relative.parent().unwrap_or(Path::new(""));
+    let subdirectory: PathBuf = parent.components().take(depth).collect();
+    match (subdirectory.as_os_str().is_empty(), prefix_root) {
+        (true, true) => root.display().to_string(),
+        (true, false) => ".".to_string(),
+        (false, true) => root.join(subdirectory).display().to_string(),
+        (false, false) => subdirectory.display().to_string(),
+    }
+}
+
+impl Stats {
+    fn record_attempt(&self, bytes: u64) {
+        self.attempted.fetch_add(1, Ordering::Relaxed);
+        self.attempted_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Take back the attempt of a file the run was stopped before getting to.
+    fn record_unattempted(&self, bytes: u64) {
+        self.attempted.fetch_sub(1, Ordering::Relaxed);
+        self.attempted_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.unattempted.add(bytes);
+    }
+
+    /// Count an evicted file, returning the bytes freed so far.
+    fn record_success(&self, path: &Path, bytes: u64) -> u64 {
+        let extension = path
+            .extension()
+            .map_or_else(|| "(none)".to_string(), |extension| extension.to_string_lossy().to_lowercase());
+        add_to(&self.by_extension, extension, bytes);
+        self.successful.fetch_add(1, Ordering::Relaxed);
+        self.successful_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes
+    }
+
+    fn record_failure(&self, bytes: u64) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.failed_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn by_directory(&self) -> Vec<SubdirectoryTotals> {
+        sorted_totals(&self.by_directory)
+            .into_iter()
+            .map(|(directory, count)| SubdirectoryTotals {
+                directory,
+                files: count.files,
+                bytes: count.bytes,
+            })
+            .collect()
+    }
+
+    fn by_extension(&self) -> Vec<ExtensionTotals> {
+        sorted_totals(&self.by_extension)
+            .into_iter()
+            .map(|(extension, count)| ExtensionTotals {
+                extension,
+                files: count.files,
+                bytes: count.bytes,
+            })
+            .collect()
+    }
+}
+
+/// File count and byte total for one category of skipped files.
+#[derive(Default)]
+struct Tally {
+    files: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+impl Tally {
+    fn add(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> Count {
+        Count {
+            files: self.files.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Files left out of the run, broken down by the reason they were skipped.
+#[derive(Default)]
+struct Skipped {
+    evicted: Tally,
+    too_small: Tally,
+    too_large: Tally,
+    empty: Tally,
+    pattern: Tally,
+    regex: Tally,
+    extension: Tally,
+    modified: Tally,
+    recent: Tally,
+    accessed: Tally,
+    unreadable_time: Tally,
+    ignored: Tally,
+    symlink: Tally,
+    duplicate: Tally,
+    hidden: Tally,
+    beyond_top: Tally,
+    in_use: Tally,
+    missing: Tally,
+    declined: Tally,
+    pruned_dirs: AtomicUsize,
+    named_dirs: AtomicUsize,
+    ignored_dirs: AtomicUsize,
+    hidden_dirs: AtomicUsize,
+    outside_icloud: AtomicUsize,
+    walk_errors: AtomicUsize,
+}
+
+impl Skipped {
+    /// Each category of skipped files as `(key, description, tally)`.
+    fn file_reasons(&self) -> Vec<(&'static str, &'static str, &Tally)> {
+        vec![
+            ("already_evicted", "already evicted", &self.evicted),
+            ("below_min_size", "smaller than --min-size", &self.too_small),
+            ("above_max_size", "larger than --max-size", &self.too_large),
+            ("empty", "empty", &self.empty),
+            ("pattern", "include/exclude patterns", &self.pattern),
+            ("regex", "regex filter", &self.regex),
+            ("extension", "extension filter", &self.extension),
+            ("modified_recently", "modified too recently", &self.modified),
+            ("keep_recent", "protected by --keep-recent", &self.recent),
+            ("accessed_recently", "accessed too recently", &self.accessed),
+            ("unreadable_timestamps", "unreadable timestamps", &self.unreadable_time),
+            ("evictignore", "ignore files", &self.ignored),
+            ("symlink", "symbolic links", &self.symlink),
+            ("duplicate_link", "reached through another link or directory", &self.duplicate),
+            ("hidden", "hidden", &self.hidden),
+            ("beyond_top", "not among the --top largest", &self.beyond_top),
+            ("in_use", "in use by another process", &self.in_use),
+            ("missing", "no longer exist", &self.missing),
+            ("declined", "declined interactively", &self.declined),
+        ]
+    }
+
+    /// Each category of pruned directories as `(key, description, count)`.
+    fn dir_reasons(&self) -> Vec<(&'static str, &'static str, &AtomicUsize)> {
+        vec![
+            ("exclude_pattern", "exclude patterns", &self.pruned_dirs),
+            ("prune_dir", "--prune-dir", &self.named_dirs),
+            ("evictignore", "ignore files", &self.ignored_dirs),
+            ("hidden", "hidden", &self.hidden_dirs),
+            ("outside_icloud", "not inside iCloud Drive", &self.outside_icloud),
+        ]
+    }
+
+    fn files(&self) -> usize {
+        self.file_reasons()
+            .iter()
+            .map(|(_, _, tally)| tally.files.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    fn dirs(&self) -> usize {
+        self.dir_reasons()
+            .iter()
+            .map(|(_, _, dirs)| dirs.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// Counting semaphore bounding how many evictions run at once.
+struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Gives its permit back when dropped.
+struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.permits.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        let mut permits = self.lock();
+        while *permits == 0 {
+            permits = self.released.wait(permits).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *permits -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.lock() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Paces evictions across workers to a fixed rate, handing out one slot per file.
+struct Throttle {
+    interval: Duration,
+    /// `None` once the slots run past what the clock can represent
+    next: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    fn new(per_second: f64) -> Self {
+        Self {
+            interval: Duration::try_from_secs_f64(1.0 / per_second).unwrap_or(Duration::MAX),
+            next: Mutex::new(Some(Instant::now())),
+        }
+    }
+
+    /// Wait until `files` more files may be evicted, or until `stopped` returns true.
+    fn wait(&self, files: usize, stopped: impl Fn() -> bool) {
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.next.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let slot = next.map(|next| next.max(now));
+            *next = slot.and_then(|slot| slot.checked_add(self.interval.saturating_mul(files as u32)));
+            slot
+        };
+        // A rate too low for the clock never gets to the next slot
+        sleep_unless(slot.map_or(Duration::MAX, |slot| slot - now), stopped);
+    }
+}
+
+/// Running totals for one target directory when several are given.
+struct RootTotals {
+    directory: PathBuf,
+    /// A single file was given instead of a directory
+    is_file: bool,
+    successful: Tally,
+    failed: Tally,
+}
+
+impl RootTotals {
+    /// The totals of the first root containing `path`.
+    fn find<'a>(roots: &'a [RootTotals], path: &Path) -> Option<&'a RootTotals> {
+        roots.iter().find(|root| path.starts_with(&root.directory))
+    }
+}
+
+/// Gitignore-style rules from `.gitignore`, `.icloudignore` and `.evictignore` files,
+/// loaded lazily as the walk reaches each directory.
+#[derive(Default)]
+struct EvictIgnore {
+    rules: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl EvictIgnore {
+    /// Later files win within a directory, so `.evictignore` has the final say
+    const FILE_NAMES: [&'static str; 3] = [".gitignore", ".icloudignore", ".evictignore"];
+
+    fn rules_for(&mut self, dir: &Path) -> Option<&Gitignore> {
+        self.rules
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                let files: Vec<PathBuf> = Self::FILE_NAMES
+                    .iter()
+                    .map(|name| dir.join(name))
+                    .filter(|file| file.is_file())
+                    .collect();
+                if files.is_empty() {
+                    return None;
+                }
+
+                let mut builder = GitignoreBuilder::new(dir);
+                for file in &files {
+                    if let Some(err) = builder.add(file) {
+                        warn!("Failed to read {}: {}", file.display(), err);
+                    }
+                }
+                builder
+                    .build()
+                    .map_err(|err| warn!("Failed to read the ignore files in {}: {}", dir.display(), err))
+                    .ok()
+            })
+            .as_ref()
+    }
+
+    /// Whether `path` is ignored by the closest ignore file between it and `root` that
+    /// has an opinion, so rules in deeper directories override shallower ones.
+    fn is_ignored(&mut self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        if path
+            .file_name()
+            .is_some_and(|name| Self::FILE_NAMES.iter().any(|file_name| name == *file_name))
+        {
+            // Evicting the rules would force a download on every run
+            return true;
+        }
+
+        let mut dir = path.parent();
+        while let Some(current) = dir {
+            if let Some(rules) = self.rules_for(current) {
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+            if current == root {
+                break;
+            }
+            dir = current.parent();
+        }
+        false
+    }
+}
+
+/// Whether the contents of `path` are downloaded locally.
+///
+/// Evicted files stay in place as dataless stubs flagged with `SF_DATALESS`.
+#[cfg(target_os = "macos")]
+pub fn is_materialized(path: &Path) -> io::Result<bool> {
+    use std::os::macos::fs::MetadataExt;
+
+    const SF_DATALESS: u32 = 0x4000_0000;
+    Ok(std::fs::symlink_metadata(path)?.st_flags() & SF_DATALESS == 0)
+}
+
+/// Whether the contents of `path` are downloaded locally.
+///
+/// Only macOS tracks this, so other platforms always report the status as unknown.
+#[cfg(not(target_os = "macos"))]
+pub fn is_materialized(_path: &Path) -> io::Result<bool> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "download status is only available on macOS",
+    ))
+}
+
+/// Whether a file was modified after `since`. Unreadable modification times are left
+/// to the other filters to report.
+fn is_recent(metadata: &Metadata, since: Option<SystemTime>) -> bool {
+    since.is_some_and(|since| metadata.modified().is_ok_and(|modified| modified > since))
+}
+
+/// When a file was last read or written, whichever is later.
+fn last_used(path: &Path, metadata: &Metadata) -> io::Result<SystemTime> {
+    let modified = metadata.modified();
+    match (metadata.accessed(), modified) {
+        (Ok(accessed), Ok(modified)) => Ok(accessed.max(modified)),
+        (Ok(accessed), Err(_)) => Ok(accessed),
+        (Err(err), modified) => {
+            ATIME_FALLBACK.call_once(|| {
+                warn!(
+                    "Access time unavailable ({}: {}); using modification time instead",
+                    path.display(),
+                    err
+                );
+            });
+            modified
+        }
+    }
+}
+
+/// Directories whose contents are synced by iCloud.
+///
+/// `~/Desktop` and `~/Documents` only count when "Desktop & Documents Folders" sync is
+/// on, which shows up as matching folders inside iCloud Drive.
+fn icloud_roots() -> Vec<PathBuf> {
+    let Some(home) = std::env::var_os("HOME").map(PathBuf::from) else {
+        return Vec::new();
+    };
+
+    let mobile_documents = home.join("Library/Mobile Documents");
+    let mut roots = vec![mobile_documents.clone()];
+    for name in ["Desktop", "Documents"] {
+        if mobile_documents.join("com~apple~CloudDocs").join(name).is_dir() {
+            roots.push(home.join(name));
+        }
+    }
+    roots
+}
+
+/// Whether `path` lives inside a folder synced by iCloud Drive.
+pub fn is_icloud_path(path: &Path) -> bool {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    icloud_roots()
+        .iter()
+        .any(|root| path.starts_with(std::fs::canonicalize(root).unwrap_or_else(|_| root.clone())))
+}
+
+/// Result of one eviction call, for a single file or a whole batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvictOutcome {
+    Evicted,
+    /// The eviction was refused; `exit_code` is the exit status of the tool, if any
+    Failed { exit_code: Option<i32>, error: String },
+    /// Gave up after the timeout
+    TimedOut,
+}
+
+/// Carries out the actual evictions, so the engine can run against something other than
+/// `brctl`.
+pub trait Evictor: Sync {
+    /// Evict `path`, giving up after `timeout`.
+    fn evict(&self, path: &Path, timeout: Option<Duration>) -> io::Result<EvictOutcome>;
+
+    /// Evict all of `paths`, giving up after `timeout` per file; the outcome covers the
+    /// whole batch, and failed batches are retried file by file. Defaults to one
+    /// [`evict`](Evictor::evict) call per file, stopping at the first that doesn't succeed.
+    fn evict_batch(&self, paths: &[&Path], timeout: Option<Duration>) -> io::Result<EvictOutcome> {
+        for path in paths {
+            let outcome = self.evict(path, timeout)?;
+            if outcome != EvictOutcome::Evicted {
+                return Ok(outcome);
+            }
+        }
+        Ok(EvictOutcome::Evicted)
+    }
+}
+
+/// Evicts with `brctl evict`, passing a whole batch to a single call.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BrctlEvictor;
+
+impl Evictor for BrctlEvictor {
+    fn evict(&self, path: &Path, timeout: Option<Duration>) -> io::Result<EvictOutcome> {
+        self.evict_batch(&[path], timeout)
+    }
+
+    fn evict_batch(&self, paths: &[&Path], timeout: Option<Duration>) -> io::Result<EvictOutcome> {
+        let timeout = timeout.map(|timeout| timeout.saturating_mul(paths.len() as u32));
+        match run_brctl(paths.iter().copied(), timeout) {
+            Ok(Some((status, _))) if status.success() => Ok(EvictOutcome::Evicted),
+            Ok(Some((status, stderr))) => Ok(EvictOutcome::Failed {
+                exit_code: status.code(),
+                error: if stderr.is_empty() {
+                    format!("brctl command failed (exit code: {:?})", status.code())
+                } else {
+                    format!("brctl command failed (exit code: {:?}): {}", status.code(), stderr)
+                },
+            }),
+            Ok(None) => Ok(EvictOutcome::TimedOut),
+            Err(err) => Err(io::Error::new(err.kind(), format!("brctl command error: {}", err))),
+        }
+    }
+}
+
+/// Run `brctl evict` on `files`, returning its exit status and what it wrote to stderr
+/// (trimmed, with lines joined by `; `), or `None` if it was killed for running longer
+/// than `timeout`.
+fn run_brctl<'a>(
+    files: impl IntoIterator<Item = &'a Path>,
+    timeout: Option<Duration>,
+) -> io::Result<Option<(ExitStatus, String)>> {
+    let files: Vec<&Path> = files.into_iter().collect();
+    let mut command = Command::new("brctl");
+    command
+        .arg("evict")
+        .args(&files)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    // Out of the terminal's process group, so Ctrl+C lets running evictions finish
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    trace!(
+        "Running brctl evict {}",
+        files.iter().map(|file| file.display().to_string()).collect::<Vec<_>>().join(" ")
+    );
+
+    let started = Instant::now();
+    let mut child = command.spawn()?;
+    let pid = child.id();
+    running_brctl().push(pid);
+    // Drained on its own thread so a chatty brctl can't fill the pipe and stall
+    let reader = child.stderr.take().map(|mut stderr| {
+        std::thread::spawn(move || {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text);
+            text
+        })
+    });
+    let status = match timeout {
+        None => child.wait().map(Some),
+        Some(timeout) => wait_with_timeout(child, timeout),
+    };
+    running_brctl().retain(|&running| running != pid);
+    // After a timeout, whatever brctl left running may keep the pipe open indefinitely
+    let stderr = match (&status, reader) {
+        (Ok(Some(_)), Some(reader)) => reader.join().unwrap_or_default(),
+        _ => String::new(),
+    };
+    let stderr = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("; ");
+    trace!(
+        "brctl evict of {} files finished in {:.3}s: {}",
+        files.len(),
+        started.elapsed().as_secs_f64(),
+        match &status {
+            Ok(Some(status)) => status.to_string(),
+            Ok(None) => "timed out".to_string(),
+            Err(err) => err.to_string(),
+        }
+    );
+    Ok(status?.map(|status| (status, stderr)))
+}
+
+/// Process IDs of the `brctl` calls currently running, for [`kill_running_brctl`].
+static RUNNING_BRCTL: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+fn running_brctl() -> MutexGuard<'static, Vec<u32>> {
+    RUNNING_BRCTL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Kill every `brctl` call still running, e.g. before force-quitting. Each runs in its
+/// own process group, so anything it started goes with it.
+pub fn kill_running_brctl() {
+    for pid in running_brctl().drain(..) {
+        let _ = Command::new("kill")
+            .args(["-KILL", "--", &format!("-{}", pid)])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+/// Whether a `brctl` error means retrying can't help, e.g. for files outside iCloud.
+fn is_permanent_failure(error: &str) -> bool {
+    const PERMANENT: [&str; 3] = ["not a cloud file", "not in icloud", "no such file or directory"];
+    let error = error.to_lowercase();
+    PERMANENT.iter().any(|pattern| error.contains(pattern))
+}
+
+/// Sleep for `duration`, waking up early once `stopped` returns true.
+fn sleep_unless(duration: Duration, stopped: impl Fn() -> bool) {
+    // A duration too long to add to the clock never runs out
+    let deadline = Instant::now().checked_add(duration);
+    while !stopped() {
+        let remaining = deadline.map_or(Duration::MAX, |deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining.is_zero() {
+            break;
+        }
+        std::thread::sleep(remaining.min(Duration::from_millis(50)));
+    }
+}
+
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            // The child may have exited in the meantime, which is fine
+            let _ = child.kill();
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Whether `brctl` can be started at all, so a missing or unusable binary is reported
+/// once instead of for every file.
+pub fn brctl_available() -> bool {
+    let probe = Command::new("brctl")
+        .arg("help")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    // Its exit status doesn't matter, only that it could be spawned; a file that isn't
+    // executable would fail every eviction just the same as a missing one
+    probe.is_ok()
+}
+
+/// Every file currently open by any process, from one system-wide `lsof` call so the
+/// check stays cheap on large trees.
+fn open_files() -> Result<HashSet<PathBuf>, String> {
+    let output = Command::new("lsof")
+        .args(["-w", "-F", "n"])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| format!("failed to run lsof: {}", err))?;
+    // lsof exits non-zero when some processes can't be inspected but still lists the rest
+    if output.stdout.is_empty() && !output.status.success() {
+        return Err(format!("lsof failed ({})", output.status));
+    }
+
+    Ok(output
+        .stdout
+        .split(|&byte| byte == b'\n')
+        .filter_map(|line| line.strip_prefix(b"n"))
+        .map(path_from_bytes)
+        .collect())
+}
+
+/// Read a list of paths for [`EvictOptions::file_list`] (one per line, or NUL-separated),
+/// numbered by their line in the input; `-` reads stdin. Blank entries are dropped.
+pub fn read_file_list(source: &Path, null_delimited: bool) -> io::Result<Vec<(usize, PathBuf)>> {
+    let mut input = Vec::new();
+    if source == Path::new("-") {
+        io::stdin().lock().read_to_end(&mut input)?;
+    } else {
+        File::open(source)?.read_to_end(&mut input)?;
+    }
+
+    let delimiter = if null_delimited { b'\0' } else { b'\n' };
+    Ok(input
+        .split(|&byte| byte == delimiter)
+        .map(|entry| match entry {
+            [rest @ .., b'\r'] if !null_delimited => rest,
+            _ => entry,
+        })
+        .enumerate()
+        .filter(|(_, entry)| !entry.is_empty())
+        .map(|(index, entry)| (index + 1, path_from_bytes(entry)))
+        .collect())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+/// Identifies the underlying file so several links to it are only evicted once.
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether the file name of `path` starts with a dot.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Whether `path` is the `.<name>.icloud` stub iCloud leaves behind once a file is evicted.
+fn is_icloud_placeholder(path: &Path) -> bool {
+    // Lossy, so placeholders of files with non-UTF-8 names are recognized too
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(".icloud") && name.len() > ".icloud".len() + 1)
+}
+
+/// The lowercased extension of `path`, or an empty string if it has none.
+fn extension_of(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Drop target directories that were given twice or, when `nested` is set, lie inside
+/// another target, so nothing is walked more than once.
+fn distinct_roots(directories: &[PathBuf], nested: bool) -> Vec<PathBuf> {
+    let canonical: Vec<PathBuf> = directories
+        .iter()
+        .map(|directory| std::fs::canonicalize(directory).unwrap_or_else(|_| directory.clone()))
+        .collect();
+
+    directories
+        .iter()
+        .zip(&canonical)
+        .enumerate()
+        .filter(|&(index, (directory, path))| {
+            let covered_by = canonical.iter().enumerate().position(|(other_index, other)| {
+                if path == other {
+                    other_index < index
+                } else {
+                    nested && path.starts_with(other)
+                }
+            });
+            if let Some(other_index) = covered_by {
+                warn!(
+                    "Skipping {}, already covered by {}",
+                    directory.display(),
+                    directories[other_index].display()
+                );
+            }
+            covered_by.is_none()
+        })
+        .map(|(_, (directory, _))| directory.clone())
+        .collect()
+}
+
+fn list_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The path that patterns are matched against: relative to the target directory, or
+/// the file name when a single file is the target.
+fn relative_path<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    match path.strip_prefix(root) {
+        Ok(relative) if relative.as_os_str().is_empty() => path.file_name().map_or(path, Path::new),
+        Ok(relative) => relative,
+        Err(_) => path,
+    }
+}
+
+/// Whether one of `rules` (see [`EvictOptions::prune_dir`]) matches the directory at
+/// `relative`.
+fn prunes_dir(rules: &[OsString], relative: &Path) -> bool {
+    rules.iter().any(|rule| {
+        // Any slash makes it a path, so `./build` is only the one at the top
+        if rule.to_string_lossy().contains('/') {
+            let rule: PathBuf = Path::new(rule)
+                .components()
+                .filter(|component| *component != std::path::Component::CurDir)
+                .collect();
+            relative == rule
+        } else {
+            relative.file_name() == Some(rule.as_os_str())
+        }
+    })
+}
+
+/// Whether an excluded pattern covers a whole directory, either by naming it
+/// (`**/node_modules`) or everything beneath it (`**/node_modules/**`).
+fn excludes_dir(exclude: &GlobSet, relative: &Path) -> bool {
+    let mut with_slash = relative.as_os_str().to_owned();
+    with_slash.push("/");
+    exclude.is_match(relative) || exclude.is_match(Path::new(&with_slash))
+}
+
+/// The size recorded in `metadata`, treating unreadable metadata as empty.
+fn len_or_zero(metadata: &io::Result<Metadata>) -> u64 {
+    metadata.as_ref().map_or(0, |m| m.len())
+}
+
+/// Copy a borrowed error so it can be propagated alongside a fresh one.
+fn clone_error(err: &io::Error) -> io::Error {
+    io::Error::new(err.kind(), err.to_string())
+}
+
+/// How byte counts are printed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Units {
+    /// Powers of 1024, labelled KiB, MiB, GiB
+    #[default]
+    Binary,
+    /// Powers of 1000, labelled KB, MB, GB, as Finder shows sizes
+    Si,
+}
+
+/// `bytes` in the largest unit it fills, e.g. `1.50 MiB`.
+pub fn format_bytes(bytes: u64, units: Units) -> String {
+    match units {
+        Units::Binary => scale_bytes(bytes, 1024.0, &["B", "KiB", "MiB", "GiB", "TiB"]),
+        Units::Si => scale_bytes(bytes, 1000.0, &["B", "KB", "MB", "GB", "TB"]),
+    }
+}
+
+fn scale_bytes(bytes: u64, base: f64, units: &[&str]) -> String {
+    let mut size = bytes as f64;
+    let mut unit_index = 0;
+
+    while size >= base && unit_index < units.len() - 1 {
+        size /= base;
+        unit_index += 1;
+    }
+
+    if unit_index == 0 {
+        format!("{} {}", bytes, units[unit_index])
+    } else {
+        format!("{:.2} {}", size, units[unit_index])
+    }
+}
+
+/// A file chosen for eviction, with its size and the timestamp it is sorted by.
+type SelectedFile = (PathBuf, u64, Option<SystemTime>);
+
+/// Warns once per run when access times have to be approximated by modification times.
+static ATIME_FALLBACK: Once = Once::new();
+
+/// Evict the files selected by `options` with `brctl`.
+pub fn run(options: &EvictOptions) -> EvictReport {
+    run_with(options, &BrctlEvictor, &())
+}
+
+/// Evict the files selected by `options` through `evictor`, reporting progress to
+/// `observer`.
+pub fn run_with(options: &EvictOptions, evictor: &dyn Evictor, observer: &dyn Observer) -> EvictReport {
+    let directories = distinct_roots(&options.directories, options.max_depth.is_none());
+
+    let modified_before = options
+        .older_than
+        .map(|age| SystemTime::now().checked_sub(age).unwrap_or(SystemTime::UNIX_EPOCH));
+    let recent_since = (!options.keep_recent.is_zero())
+        .then(|| SystemTime::now().checked_sub(options.keep_recent).unwrap_or(SystemTime::UNIX_EPOCH));
+    let size_range = options.min_size.unwrap_or(0)..=options.max_size.unwrap_or(u64::MAX);
+    let include = &options.include;
+    let exclude = &options.exclude;
+
+    let stats = Stats::default();
+    let skipped = Skipped::default();
+    let failures = Mutex::new(Vec::new());
+    let roots: Vec<RootTotals> = directories
+        .iter()
+        .map(|directory| RootTotals {
+            directory: directory.clone(),
+            is_file: directory.is_file(),
+            successful: Tally::default(),
+            failed: Tally::default(),
+        })
+        .collect();
+    let target_reached = AtomicBool::new(false);
+    let declined_rest = AtomicBool::new(false);
+    let stopped = || {
+        options.stop.load(Ordering::Relaxed)
+            || target_reached.load(Ordering::Relaxed)
+            || declined_rest.load(Ordering::Relaxed)
+    };
+
+    let record = |path: &Path,
+                  size_bytes: u64,
+                  outcome: Outcome,
+                  exit_code: Option<i32>,
+                  error: Option<&str>,
+                  duration: Option<Duration>| {
+        observer.file_done(&FileEvent {
+            path,
+            size_bytes,
+            outcome,
+            exit_code,
+            error,
+            duration,
+        });
+    };
+
+    // Files that can't be evicted at all are reported while collecting
+    let record_unusable = |file_path: &Path, line: Option<usize>, error: String| {
+        let reason = failure_reason(file_path, &error);
+        let error = match line {
+            Some(line) => format!("line {}: {}", line, error),
+            None => error,
+        };
+        error!("Failed evicting {} - {}", file_path.display(), error);
+        record(file_path, 0, Outcome::Failed, None, Some(&error), None);
+        stats.record_attempt(0);
+        stats.record_failure(0);
+        if let Some(root) = RootTotals::find(&roots, file_path) {
+            root.failed.add(0);
+        }
+        failures
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(Failure {
+                path: file_path.display().to_string(),
+                error,
+                reason,
+            });
+    };
+
+    // Biggest wins first, so a space target is hit with as few evictions as possible
+    let sort = options.sort.or(options.free.map(|_| SortOrder::SizeDesc));
+    // Read while the metadata is at hand, for the orders that need it
+    let sort_time = |path: &Path, metadata: &Metadata| match sort {
+        Some(SortOrder::MtimeAsc | SortOrder::MtimeDesc) => metadata.modified().ok(),
+        Some(SortOrder::AtimeAsc | SortOrder::AtimeDesc) => last_used(path, metadata).ok(),
+        _ => None,
+    };
+
+    // Hands each selected file to `emit`
+    let walk = |emit: &mut dyn FnMut(SelectedFile)| {
+        if let Some(file_list) = &options.file_list {
+            for (line, file_path) in file_list {
+                if stopped() {
+                    break;
+                }
+                let error = match std::fs::metadata(file_path) {
+                    Err(err) if options.skip_missing && err.kind() == io::ErrorKind::NotFound => {
+                        skip(&skipped.missing, file_path, 0, "no longer exists");
+                        continue;
+                    }
+                    Err(err) => err.to_string(),
+                    Ok(metadata) if !metadata.is_file() => "not a regular file".to_string(),
+                    Ok(_) if !options.allow_non_icloud && !is_icloud_path(file_path) => {
+                        "not inside iCloud Drive; pass --allow-non-icloud to evict it anyway".to_string()
+                    }
+                    Ok(metadata) if metadata.len() == 0 && !options.include_empty => {
+                        skip(&skipped.empty, file_path, 0, "empty");
+                        continue;
+                    }
+                    Ok(metadata) if is_recent(&metadata, recent_since) => {
+                        skip(&skipped.recent, file_path, metadata.len(), "protected by --keep-recent");
+                        continue;
+                    }
+                    Ok(metadata) => {
+                        emit((file_path.clone(), metadata.len(), sort_time(file_path, &metadata)));
+                        continue;
+                    }
+                };
+
+                record_unusable(file_path, Some(*line), error);
+            }
+        } else {
+            let mut ignore_rules = EvictIgnore::default();
+            let mut seen_files = HashSet::new();
+            for directory in &directories {
+                if !options.allow_non_icloud && !is_icloud_path(directory) {
+                    warn!("Skipping {}: not inside iCloud Drive", directory.display());
+                    skipped.outside_icloud.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let absolute_root = std::fs::canonicalize(directory).unwrap_or_else(|_| directory.clone());
+                let mut walker = WalkDir::new(directory).follow_links(options.follow_symlinks);
+                if let Some(depth) = options.max_depth {
+                    walker = walker.max_depth(depth);
+                }
+                if let Some(depth) = options.min_depth {
+                    walker = walker.min_depth(depth);
+                }
+
+                let selected = walker
+                    .into_iter()
+                    .filter_entry(|entry| {
+                        if entry.depth() == 0 {
+                            return true;
+                        }
+                        let is_dir = entry.file_type().is_dir();
+
+                        if is_dir && prunes_dir(&options.prune_dir, relative_path(directory, entry.path())) {
+                            skip_dir(&skipped.named_dirs, entry.path(), "--prune-dir");
+                            return false;
+                        }
+
+                        if !options.no_ignore && ignore_rules.is_ignored(directory, entry.path(), is_dir) {
+                            if is_dir {
+                                skip_dir(&skipped.ignored_dirs, entry.path(), "ignore files");
+                            } else {
+                                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                                skip(&skipped.ignored, entry.path(), size, "ignore files");
+                            }
+                            return false;
+                        }
+
+                        if options.skip_hidden && is_hidden(entry.path()) && (is_dir || !is_icloud_placeholder(entry.path())) {
+                            if is_dir {
+                                skip_dir(&skipped.hidden_dirs, entry.path(), "hidden");
+                            } else {
+                                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                                skip(&skipped.hidden, entry.path(), size, "hidden");
+                            }
+                            return false;
+                        }
+
+                        if is_dir && !exclude.is_empty() {
+                            let relative = entry.path().strip_prefix(directory).unwrap_or(entry.path());
+                            if excludes_dir(exclude, relative) {
+                                skip_dir(&skipped.pruned_dirs, entry.path(), "exclude patterns");
+                                return false;
+                            }
+                        }
+                        true
+                    })
+                    .take_while(|_| !stopped())
+                    .filter_map(|entry| match entry {
+                        Ok(entry) => Some(entry),
+                        Err(err) => {
+                            // Includes symlink loops when following links
+                            if !options.ignore_walk_errors {
+                                warn!("Walk error: {}", err);
+                            }
+                            skipped.walk_errors.fetch_add(1, Ordering::Relaxed);
+                            None
+                        }
+                    })
+                    .filter(|entry| {
+                        if entry.path_is_symlink() && !options.follow_symlinks {
+                            skip(&skipped.symlink, entry.path(), 0, "symbolic link");
+                            return false;
+                        }
+                        entry.file_type().is_file()
+                    })
+                    // Stat each file once here and share the result across every filter below
+                    .map(|entry| {
+                        let metadata = entry.metadata().map_err(io::Error::from);
+                        (entry, metadata)
+                    })
+                    .filter(|(entry, metadata)| {
+                        // The same file can be reached through links or overlapping directories
+                        if !options.follow_symlinks && directories.len() < 2 {
+                            return true;
+                        }
+                        match metadata.as_ref().ok().and_then(file_id) {
+                            Some(id) if !seen_files.insert(id) => {
+                                let reason = "reached through another link or directory";
+                                skip(&skipped.duplicate, entry.path(), len_or_zero(metadata), reason);
+                                false
+                            }
+                            _ => true,
+                        }
+                    })
+                    .filter(|(entry, metadata)| {
+                        if options.include_evicted || options.force || !is_icloud_placeholder(entry.path()) {
+                            return true;
+                        }
+                        skip(&skipped.evicted, entry.path(), len_or_zero(metadata), "already evicted");
+                        false
+                    })
+                    .filter(|(entry, metadata)| {
+                        if include.is_empty() && exclude.is_empty() {
+                            return true;
+                        }
+
+                        let relative = relative_path(directory, entry.path());
+                        let selected = !exclude.is_match(relative) && (include.is_empty() || include.is_match(relative));
+                        if !selected {
+                            skip(&skipped.pattern, entry.path(), len_or_zero(metadata), "include/exclude patterns");
+                        }
+                        selected
+                    })
+                    .filter(|(entry, metadata)| {
+                        if options.regex.is_empty() {
+                            return true;
+                        }
+
+                        let relative = relative_path(directory, entry.path());
+                        let matched_path = if options.regex_full_path && entry.depth() == 0 {
+                            absolute_root.clone()
+                        } else if options.regex_full_path {
+                            absolute_root.join(relative)
+                        } else {
+                            relative.to_path_buf()
+                        };
+                        let matched_path = matched_path.to_string_lossy();
+                        let selected = options.regex.iter().any(|regex| regex.is_match(&matched_path));
+                        if !selected {
+                            skip(&skipped.regex, entry.path(), len_or_zero(metadata), "regex filter");
+                        }
+                        selected
+                    })
+                    .filter(|(entry, metadata)| {
+                        if options.ext.is_empty() && options.exclude_ext.is_empty() {
+                            return true;
+                        }
+
+                        let ext = extension_of(entry.path());
+                        let selected = !options.exclude_ext.contains(&ext) && (options.ext.is_empty() || options.ext.contains(&ext));
+                        if !selected {
+                            skip(&skipped.extension, entry.path(), len_or_zero(metadata), "extension filter");
+                        }
+                        selected
+                    })
+                    .filter(|(entry, metadata)| {
+                        // Leave unreadable entries in so they are reported as failures below
+                        let Ok(metadata) = metadata else {
+                            return true;
+                        };
+
+                        let size = metadata.len();
+                        if size == 0 && !options.include_empty {
+                            skip(&skipped.empty, entry.path(), 0, "empty");
+                            false
+                        } else if size < *size_range.start() {
+                            skip(&skipped.too_small, entry.path(), size, "smaller than --min-size");
+                            false
+                        } else if size > *size_range.end() {
+                            skip(&skipped.too_large, entry.path(), size, "larger than --max-size");
+                            false
+                        } else {
+                            true
+                        }
+                    })
+                    .filter(|(entry, metadata)| match metadata {
+                        Ok(metadata) if is_recent(metadata, recent_since) => {
+                            skip(&skipped.recent, entry.path(), metadata.len(), "protected by --keep-recent");
+                            false
+                        }
+                        _ => true,
+                    })
+                    .filter(|(entry, metadata)| {
+                        let Some(cutoff) = modified_before else {
+                            return true;
+                        };
+
+                        match metadata.as_ref().map_err(clone_error).and_then(|m| m.modified()) {
+                            Ok(modified) if modified <= cutoff => true,
+                            Ok(_) => {
+                                skip(&skipped.modified, entry.path(), len_or_zero(metadata), "modified too recently");
+                                false
+                            }
+                            Err(err) => {
+                                warn!("Failed to read modification time of {}: {}", entry.path().display(), err);
+                                skipped.unreadable_time.add(len_or_zero(metadata));
+                                false
+                            }
+                        }
+                    })
+                    .filter(|(entry, metadata)| {
+                        let Some(cutoff) = options.accessed_before else {
+                            return true;
+                        };
+
+                        let accessed = metadata
+                            .as_ref()
+                            .map_err(clone_error)
+                            .and_then(|m| last_used(entry.path(), m));
+
+                        match accessed {
+                            Ok(accessed) if accessed <= cutoff => true,
+                            Ok(_) => {
+                                skip(&skipped.accessed, entry.path(), len_or_zero(metadata), "accessed too recently");
+                                false
+                            }
+                            Err(err) => {
+                                warn!("Failed to read access time of {}: {}", entry.path().display(), err);
+                                skipped.unreadable_time.add(len_or_zero(metadata));
+                                false
+                            }
+                        }
+                    })
+                    .filter_map(|(entry, metadata)| match metadata {
+                        Ok(metadata) => {
+                            let time = sort_time(entry.path(), &metadata);
+                            Some((entry.into_path(), metadata.len(), time))
+                        }
+                        Err(err) => {
+                            record_unusable(entry.path(), None, format!("failed to get metadata: {}", err));
+                            None
+                        }
+                    });
+                for file in selected {
+                    emit(file);
+                }
+            }
+        }
+    };
+
+    // A rescan finding nothing is the usual case, so only the first pass warns
+    let no_files = || {
+        let level = if options.rescan { Level::Debug } else { Level::Warn };
+        if options.file_list.is_some() {
+            log!(level, "No files to evict in the file list");
+        } else if roots.iter().all(|root| root.is_file) {
+            let targets = if roots.len() == 1 { "The file" } else { "None of the files" };
+            log!(level, "{} didn't pass the filters: {}", targets, list_paths(&directories));
+        } else if skipped.files() > 0 || skipped.dirs() > 0 {
+            log!(level, "No files left to evict in {} after filtering", list_paths(&directories));
+        } else {
+            log!(level, "No files found in {}", list_paths(&directories));
+        }
+    };
+
+    // Collected first so rayon can split the work among threads, unless streaming
+    let stream = options.stream && !options.recursive_evict;
+    let mut files = Vec::new();
+    if !stream {
+        walk(&mut |file| files.push(file));
+    }
+
+    // Nothing to reclaim from files that are already evicted, so they stay out of the
+    // selection and the total a dry run projects; one stat per file, so checked in
+    // parallel. If the status can't be determined, attempt the eviction anyway
+    if !options.force && !options.assume_downloaded && !stream {
+        let materialized: Vec<bool> = files
+            .par_iter()
+            .map(|(file_path, _, _)| !matches!(is_materialized(file_path), Ok(false)))
+            .collect();
+        let mut materialized = materialized.into_iter();
+        files.retain(|(file_path, file_size, _)| {
+            let keep = materialized.next().unwrap_or(true);
+            if !keep {
+                skip(&skipped.evicted, file_path, *file_size, "already evicted");
+            }
+            keep
+        });
+    }
+
+    if let Some(top) = options.top {
+        files.sort_by_key(|&(_, size, _)| std::cmp::Reverse(size));
+        for (path, size, _) in files.iter().skip(top) {
+            skip(&skipped.beyond_top, path, *size, "not among the --top largest");
+        }
+        files.truncate(top);
+    }
+
+    // Rayon still splits the list across threads, so the order is best-effort
+    match sort {
+        Some(SortOrder::SizeDesc) => files.sort_by_key(|&(_, size, _)| std::cmp::Reverse(size)),
+        Some(SortOrder::SizeAsc) => files.sort_by_key(|&(_, size, _)| size),
+        Some(SortOrder::Path) => files.sort(),
+        Some(SortOrder::MtimeAsc | SortOrder::AtimeAsc) => files.sort_by_key(|&(_, _, time)| time),
+        Some(SortOrder::MtimeDesc | SortOrder::AtimeDesc) => files.sort_by_key(|&(_, _, time)| std::cmp::Reverse(time)),
+        None => {}
+    }
+
+    let mut selected = Count {
+        files: files.len(),
+        bytes: files.iter().map(|&(_, size, _)| size).sum(),
+    };
+    let mut cancelled = false;
+    if stopped() {
+        // Interrupted while collecting; evict nothing
+    } else if !stream && files.is_empty() {
+        no_files();
+    } else if !(if stream { observer.streaming() } else { observer.starting(selected.files, selected.bytes) }) {
+        cancelled = true;
+    } else {
+        let open = if options.evict_open_files {
+            None
+        } else {
+            match open_files() {
+                Ok(open) => Some(open),
+                Err(err) => {
+                    warn!("Warning: could not check for open files, evicting them too: {}", err);
+                    None
+                }
+            }
+        };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.concurrency)
+            .build()
+            .expect("Failed to build thread pool");
+
+        let record_success = |file_path: &Path, file_size: u64, duration: Option<Duration>| {
+            if options.dry_run {
+                record(file_path, file_size, Outcome::WouldEvict, None, None, None);
+            } else {
+                record(file_path, file_size, Outcome::Success, Some(0), None, duration);
+            }
+            if let Some(root) = RootTotals::find(&roots, file_path) {
+                root.successful.add(file_size);
+                // Files given one by one, e.g. through a shell glob, are grouped by where they are
+                let key = match (root.is_file, root.directory.parent()) {
+                    (true, Some(parent)) if !parent.as_os_str().is_empty() => parent.display().to_string(),
+                    (true, _) => ".".to_string(),
+                    (false, _) => subdirectory_key(&root.directory, file_path, options.by_dir_depth, roots.len() > 1),
+                };
+                add_to(&stats.by_directory, key, file_size);
+            }
+            let freed = stats.record_success(file_path, file_size);
+            if let Some(target) = options.free {
+                if freed >= target && !target_reached.swap(true, Ordering::Relaxed) {
+                    warn!("Reached the --free target of {}, stopping", format_bytes(target, options.units));
+                }
+            }
+        };
+
+        // brctl can exit 0 and leave the file in place, e.g. while it still has to upload
+        // Returns whether the file is really gone
+        let record_evicted = |file_path: &Path, file_size: u64, duration: Option<Duration>| {
+            if !options.no_verify && matches!(is_materialized(file_path), Ok(true)) {
+                info!("{} is still downloaded although brctl succeeded", file_path.display());
+                record(file_path, file_size, Outcome::NoOp, Some(0), Some("still downloaded"), duration);
+                stats.no_op.add(file_size);
+                return false;
+            }
+            info!("evicted content of '{}' ({})", file_path.display(), format_bytes(file_size, options.units));
+            record_success(file_path, file_size, duration);
+            true
+        };
+
+        let record_failure = |file_path: &Path,
+                              file_size: u64,
+                              exit_code: Option<i32>,
+                              error: String,
+                              attempts: u32,
+                              duration: Option<Duration>| {
+            let reason = failure_reason(file_path, &error);
+            let error = match attempts {
+                0 | 1 => error,
+                attempts => format!("{} (after {} attempts)", error, attempts),
+            };
+            error!(
+                "Failed evicting {} ({}) - {}",
+                file_path.display(),
+                format_bytes(file_size, options.units),
+                error
+            );
+            record(file_path, file_size, Outcome::Failed, exit_code, Some(&error), duration);
+            stats.record_failure(file_size);
+            if let Some(root) = RootTotals::find(&roots, file_path) {
+                root.failed.add(file_size);
+            }
+            failures
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(Failure {
+                    path: file_path.display().to_string(),
+                    error,
+                    reason,
+                });
+        };
+
+        let inflight = options.max_inflight.map(Semaphore::new);
+        let throttle = options.throttle.map(Throttle::new);
+        // Waits for the throttle first, so a slow start doesn't hold a permit
+        let acquire = |files: usize| {
+            if let Some(throttle) = &throttle {
+                throttle.wait(files, stopped);
+            }
+            inflight.as_ref().map(Semaphore::acquire)
+        };
+
+        // Bytes in batches still being evicted, so concurrent batches share the --free target
+        let pending = AtomicU64::new(0);
+        let evict_chunk = |mut chunk: &[SelectedFile]| {
+            while !chunk.is_empty() {
+                if stopped() {
+                    for &(_, file_size, _) in chunk {
+                        stats.unattempted.add(file_size);
+                    }
+                    return;
+                }
+
+                let mut batch = Vec::with_capacity(chunk.len());
+                let mut batch_bytes = 0;
+                let mut rest: &[SelectedFile] = &[];
+                for (index, &(ref file_path, file_size, _)) in chunk.iter().enumerate() {
+                    // Reaching --free in a dry run stops it mid-chunk too
+                    if stopped() {
+                        for &(_, file_size, _) in &chunk[index..] {
+                            stats.unattempted.add(file_size);
+                        }
+                        break;
+                    }
+
+                    // lsof reports resolved paths
+                    let in_use = open.as_ref().is_some_and(|open| {
+                        std::fs::canonicalize(file_path).is_ok_and(|path| open.contains(&path))
+                    });
+                    if in_use {
+                        skip(&skipped.in_use, file_path, file_size, "in use by another process");
+                        record(file_path, file_size, Outcome::Skipped, None, Some("in use"), None);
+                        continue;
+                    }
+
+                    match observer.decide(file_path, file_size) {
+                        Decision::Evict => {}
+                        Decision::Skip => {
+                            skip(&skipped.declined, file_path, file_size, "declined");
+                            record(file_path, file_size, Outcome::Skipped, None, Some("declined"), None);
+                            continue;
+                        }
+                        Decision::Stop => {
+                            declined_rest.store(true, Ordering::Relaxed);
+                            for &(_, file_size, _) in &chunk[index..] {
+                                stats.unattempted.add(file_size);
+                            }
+                            break;
+                        }
+                    }
+
+                    stats.record_attempt(file_size);
+
+                    if options.dry_run {
+                        info!(
+                            "[dry-run] Would evict: {} ({})",
+                            file_path.display(),
+                            format_bytes(file_size, options.units)
+                        );
+                        record_success(file_path, file_size, None);
+                        continue;
+                    }
+
+                    batch.push((file_path, file_size));
+                    // With --free, a batch ends once it covers what is left to free
+                    if let Some(target) = options.free {
+                        batch_bytes += file_size;
+                        let claimed = stats.successful_bytes.load(Ordering::Relaxed) + pending.load(Ordering::Relaxed);
+                        if claimed + batch_bytes >= target {
+                            rest = &chunk[index + 1..];
+                            break;
+                        }
+                    }
+                }
+                chunk = rest;
+                pending.fetch_add(batch_bytes, Ordering::Relaxed);
+
+                if batch.len() > 1 {
+                    let paths: Vec<&Path> = batch.iter().map(|(file_path, _)| file_path.as_path()).collect();
+                    let permit = acquire(paths.len());
+                    let started = Instant::now();
+                    let outcome = evictor.evict_batch(&paths, options.timeout);
+                    let duration = started.elapsed();
+                    drop(permit);
+                    sleep_unless(options.delay, stopped);
+                    if matches!(outcome, Ok(EvictOutcome::Evicted)) {
+                        for (file_path, file_size) in &batch {
+                            record_evicted(file_path, *file_size, Some(duration));
+                        }
+                        pending.fetch_sub(batch_bytes, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+
+                // A batch has a single outcome, so evict one file at a time to attribute
+                // failures to the right files
+                for (index, &(file_path, file_size)) in batch.iter().enumerate() {
+                    // After Ctrl+C only the file in hand is finished, not the rest of a failed batch
+                    if stopped() {
+                        for &(_, file_size) in &batch[index..] {
+                            stats.record_unattempted(file_size);
+                        }
+                        break;
+                    }
+                    let started = Instant::now();
+                    let mut retry = 0;
+                    let outcome = loop {
+                        let permit = acquire(1);
+                        let outcome = evictor.evict(file_path, options.timeout);
+                        drop(permit);
+                        sleep_unless(options.delay, stopped);
+                        let permanent = matches!(
+                            &outcome,
+                            Ok(EvictOutcome::Failed { error, .. }) if is_permanent_failure(error)
+                        );
+                        let done = permanent || stopped() || matches!(outcome, Ok(EvictOutcome::Evicted));
+                        if retry == options.retries || done {
+                            break outcome;
+                        }
+                        retry += 1;
+                        debug!("Retrying {} ({} of {})", file_path.display(), retry, options.retries);
+                        // Exponential backoff gives a busy daemon time to catch up
+                        let backoff = options.retry_delay.saturating_mul(1 << (retry - 1).min(16));
+                        sleep_unless(backoff, stopped);
+                    };
+                    let duration = Some(started.elapsed());
+                    let attempts = retry + 1;
+                    match outcome {
+                        Ok(EvictOutcome::Evicted) => {
+                            if record_evicted(file_path, file_size, duration) && retry > 0 {
+                                stats.retried.add(file_size);
+                            }
+                        }
+                        Ok(EvictOutcome::Failed { exit_code, error }) => {
+                            record_failure(file_path, file_size, exit_code, error, attempts, duration);
+                        }
+                        Ok(EvictOutcome::TimedOut) => {
+                            let timeout = options.timeout.unwrap_or_default();
+                            let error = format!("timed out after {}s", timeout.as_secs_f64());
+                            stats.timed_out.add(file_size);
+                            record_failure(file_path, file_size, None, error, attempts, duration);
+                        }
+                        Err(err) => {
+                            record_failure(file_path, file_size, None, err.to_string(), attempts, duration);
+                        }
+                    }
+                }
+                pending.fetch_sub(batch_bytes, Ordering::Relaxed);
+            }
+        };
+
+        // All of a target's files share the outcome of the one call evicting it
+        let evict_root = |root: &RootTotals, files: &[&SelectedFile]| {
+            for &&(_, file_size, _) in files {
+                stats.record_attempt(file_size);
+            }
+            if options.dry_run {
+                info!("[dry-run] Would evict: {} ({} files)", root.directory.display(), files.len());
+                for (file_path, file_size, _) in files {
+                    record_success(file_path, *file_size, None);
+                }
+                return;
+            }
+
+            let permit = acquire(files.len());
+            let started = Instant::now();
+            let timeout = options.timeout.map(|timeout| timeout.saturating_mul(files.len().max(1) as u32));
+            let outcome = evictor.evict(&root.directory, timeout);
+            let duration = Some(started.elapsed());
+            drop(permit);
+            let error = match outcome {
+                Ok(EvictOutcome::Evicted) => {
+                    for (file_path, file_size, _) in files {
+                        record_evicted(file_path, *file_size, duration);
+                    }
+                    return;
+                }
+                Ok(EvictOutcome::Failed { exit_code, error }) => (exit_code, error),
+                Ok(EvictOutcome::TimedOut) => {
+                    for &&(_, file_size, _) in files {
+                        stats.timed_out.add(file_size);
+                    }
+                    let timeout = timeout.unwrap_or_default();
+                    (None, format!("timed out after {}s", timeout.as_secs_f64()))
+                }
+                Err(err) => (None, err.to_string()),
+            };
+            for (file_path, file_size, _) in files {
+                record_failure(file_path, *file_size, error.0, error.1.clone(), 1, duration);
+            }
+        };
+
+        let batch_size = options.batch_size.max(1);
+        if options.recursive_evict {
+            for root in &roots {
+                let in_root: Vec<&SelectedFile> = files
+                    .iter()
+                    .filter(|(file_path, _, _)| {
+                        RootTotals::find(&roots, file_path).is_some_and(|found| std::ptr::eq(found, root))
+                    })
+                    .collect();
+                if in_root.is_empty() {
+                    continue;
+                }
+                if stopped() {
+                    for &&(_, file_size, _) in &in_root {
+                        stats.unattempted.add(file_size);
+                    }
+                    continue;
+                }
+                evict_root(root, &in_root);
+            }
+        } else if stream {
+            // Bounded, so a walk that outpaces brctl doesn't pile up paths in memory
+            let (sender, receiver) = mpsc::sync_channel::<Vec<SelectedFile>>(options.concurrency.max(1) * 2);
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    pool.install(|| receiver.into_iter().par_bridge().for_each(|chunk| evict_chunk(&chunk)))
+                });
+                let mut chunk = Vec::with_capacity(batch_size);
+                walk(&mut |file| {
+                    // Checked as files are found, where a collected run checks them all at once
+                    if !options.force && !options.assume_downloaded && matches!(is_materialized(&file.0), Ok(false)) {
+                        skip(&skipped.evicted, &file.0, file.1, "already evicted");
+                        return;
+                    }
+                    selected.files += 1;
+                    selected.bytes += file.1;
+                    chunk.push(file);
+                    if chunk.len() == batch_size {
+                        let _ = sender.send(std::mem::replace(&mut chunk, Vec::with_capacity(batch_size)));
+                    }
+                });
+                if !chunk.is_empty() {
+                    let _ = sender.send(chunk);
+                }
+                drop(sender);
+                observer.walk_done(selected.files, selected.bytes);
+            });
+            if selected.files == 0 && !stopped() {
+                no_files();
+            }
+        } else {
+            pool.install(|| files.par_chunks(batch_size).for_each(evict_chunk));
+        }
+    }
+
+    let successful_bytes = stats.successful_bytes.load(Ordering::Relaxed);
+    EvictReport {
+        directories,
+        interrupted: options.stop.load(Ordering::Relaxed),
+        cancelled,
+        selected,
+        attempted: Count {
+            files: stats.attempted.load(Ordering::Relaxed),
+            bytes: stats.attempted_bytes.load(Ordering::Relaxed),
+        },
+        successful: Count {
+            files: stats.successful.load(Ordering::Relaxed),
+            bytes: successful_bytes,
+        },
+        failed: Count {
+            files: stats.failed.load(Ordering::Relaxed),
+            bytes: stats.failed_bytes.load(Ordering::Relaxed),
+        },
+        no_op: stats.no_op.count(),
+        timed_out: stats.timed_out.count(),
+        retried: stats.retried.count(),
+        unattempted: stats.unattempted.count(),
+        // Single files show up in `by_directory` instead
+        per_directory: if roots.iter().filter(|root| !root.is_file).count() > 1 {
+            roots
+                .iter()
+                .filter(|root| !root.is_file)
+                .map(|root| DirectoryTotals {
+                    directory: root.directory.display().to_string(),
+                    successful: root.successful.count(),
+                    failed: root.failed.count(),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        },
+        by_directory: stats.by_directory(),
+        by_extension: stats.by_extension(),
+        free_target_bytes: options.free,
+        free_target_met: options.free.map(|target| successful_bytes >= target),
+        skipped: skipped
+            .file_reasons()
+            .into_iter()
+            .map(|(reason, description, tally)| SkippedFiles {
+                reason,
+                description,
+                files: tally.files.load(Ordering::Relaxed),
+                bytes: tally.bytes.load(Ordering::Relaxed),
+            })
+            .collect(),
+        skipped_directories: skipped
+            .dir_reasons()
+            .into_iter()
+            .map(|(reason, description, dirs)| SkippedDirs {
+                reason,
+                description,
+                directories: dirs.load(Ordering::Relaxed),
+            })
+            .collect(),
+        walk_errors: skipped.walk_errors.load(Ordering::Relaxed),
+        failures: failures.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every path it is asked to evict, and evicts them all unless `fail` is set
+    /// or their file name is scripted to fail.
+    #[derive(Default)]
+    struct MockEvictor {
+        evicted: Mutex<Vec<PathBuf>>,
+        /// Number of paths in each `evict_batch` call
+        batches: Mutex<Vec<usize>>,
+        fail: bool,
+        /// Fail these for good
+        broken: Vec<&'static str>,
+        /// Fail these with an error worth retrying, this many more times each
+        flaky: Mutex<HashMap<&'static str, u32>>,
+    }
+
+    impl MockEvictor {
+        fn calls(&self, name: &str) -> usize {
+            self.evicted.lock().unwrap().iter().filter(|path| path.ends_with(name)).count()
+        }
+    }
+
+    impl Evictor for MockEvictor {
+        fn evict(&self, path: &Path, _timeout: Option<Duration>) -> io::Result<EvictOutcome> {
+            self.evicted.lock().unwrap().push(path.to_path_buf());
+            let name = path.file_name().unwrap().to_string_lossy();
+            if self.fail || self.broken.contains(&name.as_ref()) {
+                return Ok(EvictOutcome::Failed {
+                    exit_code: Some(1),
+                    error: "not a cloud file".to_string(),
+                });
+            }
+            if let Some(left) = self.flaky.lock().unwrap().get_mut(name.as_ref()).filter(|left| **left > 0) {
+                *left -= 1;
+                return Ok(EvictOutcome::Failed {
+                    exit_code: Some(1),
+                    error: "resource busy".to_string(),
+                });
+            }
+            Ok(EvictOutcome::Evicted)
+        }
+
+        fn evict_batch(&self, paths: &[&Path], timeout: Option<Duration>) -> io::Result<EvictOutcome> {
+            self.batches.lock().unwrap().push(paths.len());
+            for path in paths {
+                let outcome = self.evict(path, timeout)?;
+                if outcome != EvictOutcome::Evicted {
+                    return Ok(outcome);
+                }
+            }
+            Ok(EvictOutcome::Evicted)
+        }
+    }
+
+    /// An empty directory of its own for `test`.
+    fn test_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("evict-icloud-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write each of `files` as `(name, size)` into `dir`.
+    fn write_files(dir: &Path, files: &[(&str, usize)]) {
+        for &(name, size) in files {
+            std::fs::write(dir.join(name), vec![b'x'; size]).unwrap();
+        }
+    }
+
+    fn options(directory: &Path) -> EvictOptions {
+        EvictOptions {
+            directories: vec![directory.to_path_buf()],
+            concurrency: 1,
+            keep_recent: Duration::ZERO,
+            evict_open_files: true,
+            allow_non_icloud: true,
+            ..EvictOptions::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn evicts_non_utf8_paths_unchanged() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = test_dir("non-utf8");
+        let file = dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(&file, "contents").unwrap();
+
+        let evictor = MockEvictor::default();
+        let report = run_with(&options(&dir), &evictor, &());
+        assert_eq!(report.successful.files, 1);
+        assert_eq!(*evictor.evicted.lock().unwrap(), vec![file.clone()]);
+
+        // The same bytes read back from a file list
+        let list = dir.join("list");
+        std::fs::write(&list, [file.as_os_str().as_bytes(), b"\n"].concat()).unwrap();
+        assert_eq!(read_file_list(&list, false).unwrap(), vec![(1, file)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recognizes_non_utf8_placeholders() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = |name: &[u8]| PathBuf::from(std::ffi::OsStr::from_bytes(name));
+        assert!(is_icloud_placeholder(&path(b"photos/.caf\xe9.jpg.icloud")));
+        assert!(!is_icloud_placeholder(&path(b"photos/caf\xe9.jpg")));
+        assert!(!is_icloud_placeholder(&path(b"photos/.icloud")));
+
+        // The placeholder is left alone while the downloaded file is attempted, and its
+        // failure reported under the lossy name
+        let dir = test_dir("non-utf8-placeholder");
+        std::fs::write(dir.join(std::ffi::OsStr::from_bytes(b".caf\xe9.jpg.icloud")), "stub").unwrap();
+        std::fs::write(dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.jpg")), "contents").unwrap();
+        let evictor = MockEvictor {
+            fail: true,
+            ..MockEvictor::default()
+        };
+        let report = run_with(&options(&dir), &evictor, &());
+        assert_eq!(*evictor.evicted.lock().unwrap(), vec![dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.jpg"))]);
+        assert_eq!(report.attempted.files, 1);
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.failures[0].path.ends_with("caf\u{fffd}.jpg"));
+        let evicted = report.skipped.iter().find(|skipped| skipped.reason == "already_evicted");
+        assert_eq!(evicted.map(|skipped| skipped.files), Some(1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prune_rules_with_a_slash_are_root_relative() {
+        let rules = [OsString::from("node_modules"), OsString::from("./build"), OsString::from("photos/raw")];
+        assert!(prunes_dir(&rules, Path::new("node_modules")));
+        assert!(prunes_dir(&rules, Path::new("app/node_modules")));
+        assert!(prunes_dir(&rules, Path::new("build")));
+        assert!(!prunes_dir(&rules, Path::new("app/build")));
+        assert!(prunes_dir(&rules, Path::new("photos/raw")));
+        assert!(!prunes_dir(&rules, Path::new("old/photos/raw")));
+        assert!(!prunes_dir(&rules, Path::new("raw")));
+    }
+
+    #[test]
+    fn leaves_targets_outside_icloud_alone() {
+        let dir = test_dir("outside-icloud");
+        std::fs::write(dir.join("a.txt"), "contents").unwrap();
+
+        let evictor = MockEvictor::default();
+        let report = run_with(&EvictOptions { allow_non_icloud: false, ..options(&dir) }, &evictor, &());
+        assert!(evictor.evicted.lock().unwrap().is_empty());
+        assert_eq!(report.attempted.files, 0);
+        let outside = report.skipped_directories.iter().find(|skipped| skipped.reason == "outside_icloud");
+        assert_eq!(outside.map(|skipped| skipped.directories), Some(1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_batch_is_split_into_single_files() {
+        let dir = test_dir("split-batch");
+        write_files(&dir, &[("a", 100), ("b", 200), ("c", 300), ("d", 400)]);
+
+        let evictor = MockEvictor {
+            broken: vec!["c"],
+            ..MockEvictor::default()
+        };
+        let report = run_with(&EvictOptions { batch_size: 4, ..options(&dir) }, &evictor, &());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(*evictor.batches.lock().unwrap(), [4]);
+        // Once in the batch and once on its own
+        assert_eq!(evictor.calls("c"), 2);
+        assert_eq!(evictor.calls("d"), 1);
+        assert_eq!(report.attempted, Count { files: 4, bytes: 1000 });
+        assert_eq!(report.successful, Count { files: 3, bytes: 700 });
+        assert_eq!(report.failed, Count { files: 1, bytes: 300 });
+        assert_eq!(report.retried, Count::default());
+        assert_eq!(report.unattempted, Count::default());
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.failures[0].path.ends_with("c"));
+        assert_eq!(report.failures[0].reason, "not a cloud file");
+    }
+
+    #[test]
+    fn retries_count_only_the_last_attempt() {
+        let dir = test_dir("retries");
+        write_files(&dir, &[("flaky", 100), ("broken", 200), ("busy", 300), ("fine", 400)]);
+
+        let evictor = MockEvictor {
+            broken: vec!["broken"],
+            flaky: Mutex::new(HashMap::from([("flaky", 2), ("busy", u32::MAX)])),
+            ..MockEvictor::default()
+        };
+        let options = EvictOptions {
+            batch_size: 1,
+            retries: 2,
+            retry_delay: Duration::ZERO,
+            ..options(&dir)
+        };
+        let report = run_with(&options, &evictor, &());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(evictor.calls("flaky"), 3);
+        // Permanent errors aren't retried
+        assert_eq!(evictor.calls("broken"), 1);
+        assert_eq!(evictor.calls("busy"), 3);
+        assert_eq!(report.attempted, Count { files: 4, bytes: 1000 });
+        assert_eq!(report.successful, Count { files: 2, bytes: 500 });
+        assert_eq!(report.retried, Count { files: 1, bytes: 100 });
+        assert_eq!(report.failed, Count { files: 2, bytes: 500 });
+        let mut failures: Vec<(&str, &str)> = report
+            .failures
+            .iter()
+            .map(|failure| (failure.reason.as_str(), failure.error.as_str()))
+            .collect();
+        failures.sort();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].0, "not a cloud file");
+        assert_eq!(failures[1].0, "resource busy");
+        assert!(failures[1].1.contains("after 3 attempts"), "{}", failures[1].1);
+    }
+
+    #[test]
+    fn free_stops_once_the_target_is_met() {
+        let dir = test_dir("free");
+        write_files(&dir, &[("a", 100), ("b", 200), ("c", 300), ("d", 400)]);
+
+        let evictor = MockEvictor::default();
+        let report = run_with(&EvictOptions { free: Some(450), ..options(&dir) }, &evictor, &());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Largest first, and the batch ends with the file that covers the target
+        assert_eq!(*evictor.batches.lock().unwrap(), [2]);
+        assert_eq!(evictor.calls("d") + evictor.calls("c"), 2);
+        assert_eq!(report.successful, Count { files: 2, bytes: 700 });
+        assert_eq!(report.failed, Count::default());
+        assert_eq!(report.unattempted, Count { files: 2, bytes: 300 });
+        assert_eq!(report.free_target_bytes, Some(450));
+        assert_eq!(report.free_target_met, Some(true));
+        assert!(!report.interrupted);
+    }
+}
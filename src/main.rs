@@ -1,18 +1,77 @@
-use std::path::PathBuf;
-use std::process::Command;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::Parser;
-use rayon::prelude::*;
-use walkdir::WalkDir;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgGroup, CommandFactory, Parser, ValueEnum};
+use evict_icloud::{
+    brctl_available, format_bytes, is_icloud_path, is_materialized, read_file_list, BrctlEvictor, Decision,
+    EvictOptions, EvictReport, Evictor, FileEvent, Observer, Outcome, SortOrder, Units,
+};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use log::{error, info, warn, Level, LevelFilter, Log, Metadata as LogMetadata, Record};
+use regex::Regex;
+use serde::Serialize;
+
+mod profile;
 
 /// Evict downloaded iCloud files inside a directory tree using `brctl evict`.
 #[derive(Parser, Debug)]
-#[command(name = "evict-icloud", version, about)]
+#[command(
+    name = "evict-icloud",
+    version,
+    about,
+    group(ArgGroup::new("path_lists").args(["files_from", "retry_from", "failed_out", "print_paths"]).multiple(true)),
+    after_help = "Exit status:\n  0    every selected file was evicted (or would be, with --dry-run)\n  1    at least one file could not be evicted\n  2    invalid arguments or environment, such as a missing target or brctl\n  130  interrupted with Ctrl+C\n  131  force quit with a second Ctrl+C"
+)]
 struct Cli {
-    /// Target directory to process
-    directory: PathBuf,
+    /// Start from the options saved under this name in
+    /// `~/.config/evict-icloud/config.toml`; options given here override them
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Target directories or files to process
+    #[arg(
+        value_name = "DIRECTORY",
+        required_unless_present_any = ["files_from", "retry_from"],
+        conflicts_with_all = ["files_from", "retry_from"]
+    )]
+    directories: Vec<PathBuf>,
+
+    /// Evict the files listed in this file (one path per line, `-` for stdin) instead of
+    /// walking a directory; the walk filters don't apply to listed files
+    #[arg(long, visible_alias = "from-file", value_name = "PATH")]
+    files_from: Option<PathBuf>,
+
+    /// Write the path of every file that fails to this file as the failures happen, for
+    /// a later --retry-from
+    #[arg(long, value_name = "PATH")]
+    failed_out: Option<PathBuf>,
+
+    /// Remember the evicted files in this JSON file, and report the ones an earlier run
+    /// evicted that have been downloaded again since
+    #[arg(long, value_name = "PATH")]
+    state: Option<PathBuf>,
+
+    /// Only retry the files listed in a --failed-out file, skipping the walk; files that
+    /// no longer exist are counted as skipped rather than failed
+    #[arg(long, value_name = "PATH", conflicts_with = "files_from")]
+    retry_from: Option<PathBuf>,
+
+    /// Entries in --files-from, --retry-from, --failed-out and --print-paths are separated
+    /// by NUL bytes, as printed by `find -print0`
+    #[arg(short = '0', long = "null", requires = "path_lists")]
+    null: bool,
 
     /// Maximum number of concurrent evictions (defaults to logical CPU count)
     #[arg(short, long)]
@@ -21,140 +80,2215 @@ struct Cli {
     /// Print the file paths that would be evicted without executing `brctl evict`
     #[arg(short, long)]
     dry_run: bool,
+
+    /// Only print how many files (and bytes) would be evicted, per subdirectory and
+    /// extension, without listing them or running `brctl`; files aren't checked for
+    /// being evicted already, so those are counted too
+    #[arg(long, visible_alias = "stats-only")]
+    count: bool,
+
+    /// Don't ask for confirmation before evicting
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Ask about every selected file before evicting it: `y` evicts it, `n` skips it, `a`
+    /// evicts it and all the rest, `q` stops; files are evicted one at a time
+    #[arg(short, long, conflicts_with_all = ["yes", "watch", "count"])]
+    interactive: bool,
+
+    /// Print the path of every evicted file (or every file a dry run would evict) to
+    /// stdout, which otherwise stays empty; all other output goes to stderr
+    #[arg(long)]
+    print_paths: bool,
+
+    /// Output style; `json` prints a single summary document to stdout instead of
+    /// per-file lines, and `jsonl` prints one JSON object per file followed by the summary
+    #[arg(long, visible_alias = "output", value_enum, default_value_t = Format::Human)]
+    format: Format,
+
+    /// Shorthand for `--format json`
+    #[arg(long, conflicts_with = "format")]
+    json: bool,
+
+    /// Print a line for every evicted file instead of showing a progress bar on
+    /// terminals; repeat to also list skipped files (`-vv`) and `brctl` calls (`-vvv`).
+    /// These are the `info`, `debug` and `trace` log levels, and `RUST_LOG` overrides them
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print errors and the summary, with no per-file lines, progress bar or notes
+    /// (the `error` log level); with `--format json` stdout holds nothing but the JSON document
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print a `progress:` line with the running totals every this many seconds instead of
+    /// showing a progress bar, e.g. when stderr goes to a file; `0` turns it off
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    stats_interval: u64,
+
+    /// Print the same `progress:` line after every N processed files instead of showing a
+    /// progress bar; without a bar, e.g. when stderr is not a terminal, this happens every
+    /// 100 files unless `--quiet` is given. `0` turns it off
+    #[arg(long, value_name = "N")]
+    progress_every: Option<usize>,
+
+    /// Color evicted files green, skipped ones yellow and failures red; `auto` colors
+    /// only terminals, and setting `NO_COLOR` turns colors off
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Print sizes in binary (KiB, MiB: powers of 1024) or SI units (KB, MB: powers of
+    /// 1000, as Finder shows them)
+    #[arg(long, value_enum, value_name = "UNITS", default_value_t = Units::Binary)]
+    units: Units,
+
+    /// Write a CSV row for every processed file (path, size, outcome, exit code, error,
+    /// duration of the `brctl` call); dry runs are logged as `would-evict`
+    #[arg(long, visible_alias = "report", value_name = "PATH")]
+    csv: Option<PathBuf>,
+
+    /// Append every console message, timestamped, and the final summary to this file;
+    /// evicted and failed files are logged even when the console is quieter
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Start --log-file afresh instead of appending to it
+    #[arg(long, requires = "log_file")]
+    log_file_truncate: bool,
+
+    /// Keep running after the first pass, evicting files again whenever they have been
+    /// downloaded and then left alone for --idle; Ctrl+C stops and prints the totals
+    #[arg(long, conflicts_with_all = ["dry_run", "count", "free", "top"])]
+    watch: bool,
+
+    /// How long a file must go unused before --watch evicts it again
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "5m", requires = "watch")]
+    idle: Duration,
+
+    /// How often --watch rescans the directories for files to evict again
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1m", requires = "watch")]
+    rescan_every: Duration,
+
+    /// Post a Notification Center alert with the evicted and failed counts when the run
+    /// finishes or is interrupted
+    #[arg(long)]
+    notify: bool,
+
+    /// Follow symbolic links into the trees and files they point at; without this,
+    /// links are skipped rather than passed to `brctl evict`. Links that loop back into
+    /// the tree are reported and not followed
+    #[arg(short = 'L', long)]
+    follow_symlinks: bool,
+
+    /// Skip dotfiles and don't descend into dot-directories; `.icloud` placeholders
+    /// are still handled as already evicted files
+    #[arg(long, visible_alias = "exclude-hidden")]
+    skip_hidden: bool,
+
+    /// Never run more than this many `brctl` processes at once, however high
+    /// --concurrency is, to spare the file provider daemon
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_inflight: Option<u64>,
+
+    /// Evict at most this many files per second across all workers, e.g. `0.5` for one
+    /// every two seconds
+    #[arg(long, value_name = "OPS_PER_SEC", value_parser = parse_rate)]
+    throttle: Option<f64>,
+
+    /// Number of files passed to each `brctl evict` invocation; a failed batch is
+    /// retried one file at a time to find the files that failed
+    #[arg(long, value_name = "N", default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: u64,
+
+    /// How to evict: `brctl` runs `brctl evict`, `native` asks `NSFileManager` directly
+    /// without spawning a process per batch and falls back to `brctl` for errors it
+    /// doesn't recognize. `native` needs a macOS build with `--features native`
+    #[arg(long, value_enum, default_value_t = Backend::Brctl)]
+    backend: Backend,
+
+    /// Descend at most this many levels below the target directory (1 = direct children only)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_depth: Option<u64>,
+
+    /// Only evict files directly inside the target directory, without descending into
+    /// subdirectories (symlinked ones included); same as `--max-depth 1`
+    #[arg(long, conflicts_with_all = ["max_depth", "min_depth"])]
+    no_recurse: bool,
+
+    /// Only evict files at least this many levels below the target directory (2 = skip
+    /// files directly inside it)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    min_depth: Option<u64>,
+
+    /// Never descend into directories with this exact name (e.g. `node_modules`), or, when
+    /// it contains a `/`, at this path relative to the target directory (e.g.
+    /// `projects/current` or `./build`); can be repeated
+    #[arg(long, visible_alias = "exclude-dir", value_name = "NAME_OR_PATH")]
+    prune_dir: Vec<OsString>,
+
+    /// Skip files smaller than this size (e.g. `500K`, `100MB`, `1.5GiB`)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Skip files larger than this size (e.g. `500K`, `100MB`, `1.5GiB`)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Only evict files whose path relative to the target directory matches this glob
+    /// (e.g. `*.mov`, `Photos/*.jpg`); repeat to match any of several patterns
+    #[arg(long, value_name = "GLOB", value_parser = Glob::new)]
+    include: Vec<Glob>,
+
+    /// Never evict files whose path relative to the target directory matches this glob;
+    /// takes precedence over `--include`, and matching directories are not descended
+    /// into at all (repeatable)
+    #[arg(long, value_name = "GLOB", value_parser = Glob::new)]
+    exclude: Vec<Glob>,
+
+    /// Only evict files whose path relative to the target directory matches this regular
+    /// expression; repeat to match any of several
+    #[arg(long, value_name = "PATTERN", value_parser = Regex::new)]
+    regex: Vec<Regex>,
+
+    /// Match `--regex` against the absolute path instead of the relative one
+    #[arg(long, requires = "regex")]
+    regex_full_path: bool,
+
+    /// Only evict files with one of these extensions (case-insensitive, comma-separated
+    /// or repeated); pass an empty value (`--ext ""`) to match files without an extension
+    #[arg(long, value_name = "EXT")]
+    ext: Vec<String>,
+
+    /// Never evict files with one of these extensions (case-insensitive, comma-separated
+    /// or repeated)
+    #[arg(long, value_name = "EXT")]
+    exclude_ext: Vec<String>,
+
+    /// Also pass iCloud `.<name>.icloud` placeholders of already evicted files to `brctl evict`
+    #[arg(long)]
+    include_evicted: bool,
+
+    /// Also evict zero-byte files; by default they are skipped, as there is no space to
+    /// gain from them
+    #[arg(long)]
+    include_empty: bool,
+
+    /// Only evict files last modified longer ago than this (e.g. `12h`, `30d`, `6w`)
+    ///
+    /// DURATION is a number followed by a unit: `s` (seconds), `m` (minutes), `h`
+    /// (hours), `d` (days) or `w` (weeks). Fractions such as `1.5d` are allowed.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    older_than: Option<Duration>,
+
+    /// Never evict files modified within this window, so files being worked on stay
+    /// local; `0` turns the protection off. Also applies to --files-from
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "24h")]
+    keep_recent: Duration,
+
+    /// Only evict files last accessed before this point, given as a duration ago
+    /// (e.g. `90d`) or a UTC date (`2024-01-01`)
+    ///
+    /// DURATION uses the same units as `--older-than`. A file counts as used when it was
+    /// last read or written, whichever is later, since writes don't always update the
+    /// access time and volumes mounted with `noatime` leave it stale. Where the access
+    /// time is unavailable the modification time is used instead, with a one-time warning.
+    #[arg(long, value_name = "DURATION|DATE", value_parser = parse_cutoff)]
+    accessed_before: Option<SystemTime>,
+
+    /// Don't print unreadable directories and other walk errors; they are still counted
+    /// in the summary
+    #[arg(long)]
+    ignore_walk_errors: bool,
+
+    /// Don't read `.evictignore`, `.icloudignore` or `.gitignore` files
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// Stop once this much space has been reclaimed (e.g. `20GB`), evicting the largest
+    /// files first; batches end once they cover what is left, so at most one file per
+    /// thread goes past the target
+    #[arg(long, visible_alias = "free-target", value_name = "SIZE", value_parser = parse_size)]
+    free: Option<u64>,
+
+    /// Kill a `brctl` call that takes longer than this (e.g. `30s`) and count the file as
+    /// failed; batches get this much time per file. Without it, calls can block forever
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Retry a failed eviction up to this many more times before counting the file as
+    /// failed; permanent errors such as "not a cloud file" aren't retried
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// How long to wait before the first retry; each further retry waits twice as long
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s")]
+    retry_delay: Duration,
+
+    /// Pause each worker this long after every eviction (e.g. `500ms`) to go easy on the
+    /// file provider; Ctrl+C cuts the pause short
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "0")]
+    delay: Duration,
+
+    /// Order to evict the selected files in; the order is best-effort since files are
+    /// evicted in parallel, but it decides what gets done first when the run is
+    /// interrupted or stops at --free (which defaults to `size-desc`)
+    #[arg(long, value_enum, value_name = "ORDER")]
+    sort: Option<SortOrder>,
+
+    /// Evict the largest files first; same as `--sort size-desc`
+    #[arg(long, conflicts_with = "sort")]
+    largest_first: bool,
+
+    /// Only attempt the N largest files that pass the filters
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Start evicting while the directories are still being walked instead of collecting
+    /// every file first, which keeps memory flat on huge trees; the total of files is only
+    /// known once the walk is over
+    #[arg(long, conflicts_with_all = ["sort", "largest_first", "top"])]
+    stream: bool,
+
+    /// Evict each target with a single `brctl evict <directory>` call that recurses by
+    /// itself, instead of one call per batch of files; the files are only counted for the
+    /// summary, so per-file filters can't be used. Ignore files, --keep-recent and the
+    /// open-file check can't be honored either, so they must be turned off explicitly with
+    /// --no-ignore, --keep-recent 0 and --evict-open-files
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "files_from", "retry_from", "include", "exclude", "regex", "ext", "exclude_ext", "min_size",
+            "max_size", "older_than", "accessed_before", "skip_hidden", "prune_dir",
+            "max_depth", "min_depth", "no_recurse", "top", "free", "sort", "largest_first", "interactive",
+            "stream", "watch",
+        ]
+    )]
+    recursive_evict: bool,
+
+    /// Group the summary's per-directory totals this many levels below each target
+    /// directory (`1` = its direct subdirectories)
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    by_dir_depth: u64,
+
+    /// List every extension in the summary's per-extension totals instead of the top ten
+    #[arg(long)]
+    by_extension: bool,
+
+    /// Evict files even while another process has them open (checked with `lsof`)
+    #[arg(long)]
+    evict_open_files: bool,
+
+    /// Run even if the target directory is not inside iCloud Drive
+    #[arg(long)]
+    allow_non_icloud: bool,
+
+    /// Attempt eviction even for files that already look evicted (dataless files and
+    /// `.icloud` placeholders), in case detection is wrong on this macOS version
+    #[arg(long)]
+    force: bool,
+
+    /// Trust `brctl`'s exit status instead of checking that each evicted file is really
+    /// gone; files that are still downloaded are otherwise counted as no-ops
+    #[arg(long)]
+    no_verify: bool,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Human,
+    Json,
+    Jsonl,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    Brctl,
+    Native,
+}
 
-fn format_bytes(bytes: u64) -> String {
-    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
-    let mut size = bytes as f64;
-    let mut unit_index = 0;
-    
-    while size >= 1024.0 && unit_index < UNITS.len() - 1 {
-        size /= 1024.0;
-        unit_index += 1;
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color output going to a stream that is (or isn't) a terminal.
+    fn enabled(self, terminal: bool) -> bool {
+        if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+            return false;
+        }
+        match self {
+            ColorChoice::Auto => terminal,
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
     }
-    
-    if unit_index == 0 {
-        format!("{} {}", bytes, UNITS[unit_index])
+}
+
+const BOLD: &str = "1";
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+
+/// Wrap `text` in the ANSI escape code `style` when `color` is set.
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", style, text)
     } else {
-        format!("{:.2} {}", size, UNITS[unit_index])
+        text.to_string()
     }
 }
 
-fn print_summary(stats: &Arc<(AtomicUsize, AtomicUsize, AtomicUsize, AtomicU64, AtomicU64, AtomicU64)>) {
-    let attempted = stats.0.load(Ordering::Relaxed);
-    let successful = stats.1.load(Ordering::Relaxed);
-    let failed = stats.2.load(Ordering::Relaxed);
-    let attempted_bytes = stats.3.load(Ordering::Relaxed);
-    let successful_bytes = stats.4.load(Ordering::Relaxed);
-    let failed_bytes = stats.5.load(Ordering::Relaxed);
+/// Console logger that lifts the progress bar out of the way of each line, and tees
+/// records to the `--log-file`.
+struct Logger {
+    inner: env_logger::Logger,
+    progress: Mutex<Option<ProgressBar>>,
+    /// Records at this level and above are also written to the file
+    log_file: OnceLock<(LevelFilter, RunLog)>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Logger {
+    /// Install the logger at `level`, unless `RUST_LOG` says otherwise, coloring lines by
+    /// level when `color` is set.
+    fn init(level: LevelFilter, color: bool) {
+        let mut builder = env_logger::Builder::new();
+        if std::env::var_os("RUST_LOG").is_some() {
+            builder.parse_default_env();
+        } else {
+            builder.filter_level(LevelFilter::Warn).filter_module("evict_icloud", level);
+        }
+        let inner = builder
+            .format(move |buf, record| {
+                // Evictions are logged at info and skipped files at debug
+                let style = match record.level() {
+                    Level::Error => Some(RED),
+                    Level::Warn | Level::Debug => Some(YELLOW),
+                    Level::Info => Some(GREEN),
+                    Level::Trace => None,
+                };
+                match style {
+                    Some(style) => writeln!(buf, "{}", paint(&record.args().to_string(), style, color)),
+                    None => writeln!(buf, "{}", record.args()),
+                }
+            })
+            .build();
+        let max_level = inner.filter();
+        let logger = LOGGER.get_or_init(|| Logger {
+            inner,
+            progress: Mutex::new(None),
+            log_file: OnceLock::new(),
+        });
+        log::set_logger(logger).expect("Logger already installed");
+        log::set_max_level(max_level);
+    }
+
+    /// Tee records at `level` and above to `log_file` from now on, whatever the console
+    /// shows.
+    fn set_log_file(log_file: RunLog, level: LevelFilter) {
+        if let Some(logger) = LOGGER.get() {
+            if logger.log_file.set((level, log_file)).is_ok() {
+                log::set_max_level(log::max_level().max(level));
+            }
+        }
+    }
 
-    println!("\n=== Summary ===");
-    println!("Files attempted: {} ({})", attempted, format_bytes(attempted_bytes));
-    println!("Files successful: {} ({})", successful, format_bytes(successful_bytes));
-    println!("Files failed: {} ({})", failed, format_bytes(failed_bytes));
-    println!("Eviction complete.");
+    fn log_file() -> Option<&'static RunLog> {
+        LOGGER.get()?.log_file.get().map(|(_, log_file)| log_file)
+    }
+
+    /// Print a status line whatever the log level, and tee it to the `--log-file`.
+    fn status(text: &str) {
+        if let Some(log_file) = Self::log_file() {
+            log_file.write(text);
+        }
+        match LOGGER.get().and_then(|logger| logger.progress.lock().ok()?.clone()) {
+            Some(progress) => progress.suspend(|| eprintln!("{}", text)),
+            None => eprintln!("{}", text),
+        }
+    }
+
+    fn set_progress(progress: Option<ProgressBar>) {
+        if let Some(logger) = LOGGER.get() {
+            *logger.progress.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = progress;
+        }
+    }
 }
 
-fn main() {
-    // Enable standard backtrace via environment variable if desired.
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        self.inner.enabled(metadata) || self.log_file.get().is_some_and(|(level, _)| metadata.level() <= *level)
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some((level, log_file)) = self.log_file.get() {
+            if record.level() <= *level && record.target().starts_with("evict_icloud") {
+                log_file.write(&record.args().to_string());
+            }
+        }
+        if !self.inner.matches(record) {
+            return;
+        }
+        match self.progress.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).as_ref() {
+            Some(progress) => progress.suspend(|| self.inner.log(record)),
+            None => self.inner.log(record),
+        }
+    }
 
-    let cli = Cli::parse();
+    fn flush(&self) {
+        self.inner.flush();
+        if let Some((_, log_file)) = self.log_file.get() {
+            log_file.flush();
+        }
+    }
+}
+
+/// The evictor `--backend` asks for, or `None` if this build doesn't have it.
+fn evictor(backend: Backend) -> Option<&'static dyn Evictor> {
+    match backend {
+        Backend::Brctl => Some(&BrctlEvictor),
+        #[cfg(all(feature = "native", target_os = "macos"))]
+        Backend::Native => Some(&evict_icloud::NativeEvictor),
+        #[cfg(not(all(feature = "native", target_os = "macos")))]
+        Backend::Native => None,
+    }
+}
 
-    let concurrency = cli.concurrency.unwrap_or_else(num_cpus::get);
+/// Parse a human-readable size such as `500K`, `100MB` or `1.5GiB` into bytes.
+///
+/// Units are binary (1 KB = 1 KiB = 1024 B) whatever `--units` prints, and the unit is
+/// case-insensitive with an optional `B`/`iB` suffix.
+fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
 
-    // Collect file paths first so rayon can split work among threads
-    let files: Vec<PathBuf> = WalkDir::new(&cli.directory)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| entry.file_type().is_file())
-        .map(|entry| entry.into_path())
-        .collect();
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("expected a number followed by an optional unit, got '{}'", input))?;
 
-    if files.is_empty() {
-        eprintln!("No files found in {:?}", cli.directory);
-        return;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(format!("unknown size unit '{}' (expected B, KB, MB, GB or TB)", unit.trim())),
+    };
+
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Parse a duration such as `500ms`, `90s`, `15m`, `12h`, `30d` or `6w`.
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("expected a number followed by a unit, got '{}'", input))?;
+
+    let seconds: f64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 60.0 * 60.0,
+        "d" => 24.0 * 60.0 * 60.0,
+        "w" => 7.0 * 24.0 * 60.0 * 60.0,
+        "" if number == 0.0 => 0.0,
+        "" => return Err(format!("missing unit in '{}' (expected ms, s, m, h, d or w)", input)),
+        _ => return Err(format!("unknown duration unit '{}' (expected ms, s, m, h, d or w)", unit.trim())),
+    };
+
+    Duration::try_from_secs_f64(number * seconds).map_err(|_| format!("duration '{}' is out of range", input))
+}
+
+/// Parse a positive rate such as `2` or `0.5`.
+fn parse_rate(input: &str) -> Result<f64, String> {
+    match input.trim().parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => match Duration::try_from_secs_f64(1.0 / rate) {
+            Ok(_) => Ok(rate),
+            Err(_) => Err(format!("rate '{}' is too low", input)),
+        },
+        _ => Err(format!("expected a positive number, got '{}'", input)),
+    }
+}
+
+/// Parse a `YYYY-MM-DD` date as midnight UTC; `None` for days the month doesn't have
+/// and years too far ahead for the clock.
+fn parse_date(input: &str) -> Option<SystemTime> {
+    let mut parts = input.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) {
+        return None;
     }
 
-    let stats = Arc::new((
-        AtomicUsize::new(0), // attempted
-        AtomicUsize::new(0), // successful
-        AtomicUsize::new(0), // failed
-        AtomicU64::new(0),   // attempted bytes
-        AtomicU64::new(0),   // successful bytes
-        AtomicU64::new(0),   // failed bytes
-    ));
-
-    let shutdown_flag = Arc::new(AtomicBool::new(false));
-    let stats_clone = Arc::clone(&stats);
-    let shutdown_clone = Arc::clone(&shutdown_flag);
-
-    ctrlc::set_handler(move || {
-        println!("\nReceived Ctrl+C, stopping gracefully...");
-        shutdown_clone.store(true, Ordering::Relaxed);
-        print_summary(&stats_clone);
-        std::process::exit(0);
-    }).expect("Error setting Ctrl+C handler");
-
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(concurrency)
-        .build()
-        .expect("Failed to build thread pool");
-
-    pool.install(|| {
-        files.par_iter().for_each(|file_path| {
-            if shutdown_flag.load(Ordering::Relaxed) {
+    // Days since the Unix epoch for a proleptic Gregorian date
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era.checked_mul(146_097)?.checked_add(day_of_era - 719_468)?;
+
+    let seconds = u64::try_from(days).ok()?.checked_mul(24 * 60 * 60)?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Parse a point in time given either as a duration ago (`90d`) or a date (`2024-01-01`).
+fn parse_cutoff(input: &str) -> Result<SystemTime, String> {
+    if input.contains('-') {
+        return parse_date(input.trim()).ok_or_else(|| format!("invalid date '{}' (expected YYYY-MM-DD)", input));
+    }
+
+    let age = parse_duration(input)?;
+    Ok(SystemTime::now().checked_sub(age).unwrap_or(SystemTime::UNIX_EPOCH))
+}
+
+/// Format a count with thousands separators, e.g. `12,340`.
+fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let head = match digits.len() % 3 {
+        0 => 3.min(digits.len()),
+        head => head,
+    };
+    let mut formatted = digits[..head].to_string();
+    for group in digits.as_bytes()[head..].chunks(3) {
+        formatted.push(',');
+        formatted.push_str(std::str::from_utf8(group).expect("Digits are ASCII"));
+    }
+    formatted
+}
+
+/// Format a number of seconds as e.g. `4.2s`, `3m 05s` or `2h 03m 04s`.
+fn format_elapsed(seconds: f64) -> String {
+    if seconds < 60.0 {
+        return format!("{:.1}s", seconds);
+    }
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds)
+    } else {
+        format!("{}m {:02}s", minutes, seconds)
+    }
+}
+
+/// Format `time` as an ISO 8601 UTC timestamp such as `2024-01-01T12:00:00Z`.
+fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (days, time_of_day) = (seconds / 86_400, seconds % 86_400);
+
+    // Inverse of the day count in `parse_date`
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+/// Ask a yes/no question on the terminal; anything but `y` or `yes` declines.
+fn confirm(question: &str) -> bool {
+    eprint!("{} Proceed? [y/N] ", question);
+    let _ = io::stderr().flush();
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// One keypress from the terminal, without waiting for Enter where `stty` can arrange it;
+/// `None` once stdin is closed.
+fn read_key() -> Option<char> {
+    let saved = stty(&["-g"]);
+    let cbreak = saved.is_some() && stty(&["-icanon", "min", "1"]).is_some();
+    *saved_terminal() = saved;
+    let key = if cbreak {
+        let mut key = [0];
+        let key = io::stdin().read_exact(&mut key).ok().map(|_| char::from(key[0]));
+        eprintln!();
+        key
+    } else {
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim().chars().next().unwrap_or('\n')),
+        }
+    };
+    restore_terminal();
+    key.map(|key| key.to_ascii_lowercase())
+}
+
+/// The `stty -g` settings to go back to while [`read_key`] has changed them.
+static SAVED_TERMINAL: Mutex<Option<String>> = Mutex::new(None);
+
+fn saved_terminal() -> std::sync::MutexGuard<'static, Option<String>> {
+    SAVED_TERMINAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Undo what [`read_key`] did to the terminal, if it still needs undoing, e.g. when
+/// force quitting in the middle of a question.
+fn restore_terminal() {
+    if let Some(saved) = saved_terminal().take() {
+        stty(&[saved.trim()]);
+    }
+}
+
+/// Run `stty` on the terminal, returning its output if it succeeded.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("stty")
+        .args(args)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Split extension arguments at commas and normalize them so `.MOV`, `MOV` and `mov` all
+/// compare equal. Only a value that is empty as a whole (`--ext ""`) stands for files
+/// without an extension; empty items left by a stray comma are dropped.
+fn split_extensions(values: &[String]) -> Vec<String> {
+    let mut extensions = Vec::new();
+    for value in values {
+        if value.trim().is_empty() {
+            extensions.push(String::new());
+            continue;
+        }
+        extensions.extend(
+            value
+                .split(',')
+                .map(|item| item.trim().trim_start_matches('.').to_lowercase())
+                .filter(|item| !item.is_empty()),
+        );
+    }
+    extensions
+}
+
+/// Compile the given patterns into a single set so each path is matched once.
+fn build_glob_set(globs: &[Glob]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    builder.build().unwrap_or_else(|err| {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, format!("invalid glob pattern: {}", err))
+            .exit()
+    })
+}
+
+#[derive(Serialize)]
+struct CsvRecord<'a> {
+    path: String,
+    size_bytes: u64,
+    outcome: Outcome,
+    exit_code: Option<i32>,
+    error: &'a str,
+    duration_ms: Option<u128>,
+}
+
+/// Row-per-file log shared by all workers.
+struct CsvLog {
+    writer: Mutex<csv::Writer<File>>,
+}
+
+impl CsvLog {
+    fn create(path: &Path) -> csv::Result<Self> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_path(path)?;
+        writer.write_record(["path", "size_bytes", "outcome", "exit_code", "error", "duration_ms"])?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn record(&self, event: &FileEvent) {
+        let record = CsvRecord {
+            path: event.path.display().to_string(),
+            size_bytes: event.size_bytes,
+            outcome: event.outcome,
+            exit_code: event.exit_code,
+            error: event.error.unwrap_or(""),
+            duration_ms: event.duration.map(|duration| duration.as_millis()),
+        };
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Flush every row so a crash loses at most the file being processed
+        if let Err(err) = writer.serialize(record).map_err(io::Error::from).and_then(|_| writer.flush()) {
+            error!("Failed to write CSV row for {}: {}", event.path.display(), err);
+        }
+    }
+
+    fn flush(&self) {
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(err) = writer.flush() {
+            error!("Failed to flush CSV log: {}", err);
+        }
+    }
+}
+
+/// Timestamped plain-text record of a run, kept in the `--log-file`.
+struct RunLog {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl RunLog {
+    fn open(path: &Path, truncate: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(!truncate)
+            .truncate(truncate)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Append each line of `text` with the current time in front.
+    fn write(&self, text: &str) {
+        let timestamp = format_timestamp(SystemTime::now());
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            if let Err(err) = writeln!(file, "{} {}", timestamp, line) {
+                // Going through the logger would land right back here
+                eprintln!("Failed to write to the log file: {}", err);
                 return;
             }
+        }
+    }
+
+    fn flush(&self) {
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(err) = file.flush() {
+            eprintln!("Failed to flush the log file: {}", err);
+        }
+    }
+}
 
-            stats.0.fetch_add(1, Ordering::Relaxed);
+/// Paths of the files that failed, kept in the `--failed-out` file for a later
+/// `--retry-from`.
+struct FailedOut {
+    file: Mutex<File>,
+    delimiter: u8,
+}
 
-            // Get file size before processing
-            let file_size = match std::fs::metadata(file_path) {
-                Ok(metadata) => metadata.len(),
-                Err(err) => {
-                    eprintln!("Failed to get metadata for {}: {}", file_path.display(), err);
-                    stats.2.fetch_add(1, Ordering::Relaxed);
-                    return;
+impl FailedOut {
+    fn create(path: &Path, null_delimited: bool) -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(File::create(path)?),
+            delimiter: if null_delimited { b'\0' } else { b'\n' },
+        })
+    }
+
+    fn record(&self, path: &Path) {
+        let mut entry = path_bytes(path);
+        entry.push(self.delimiter);
+        // Unbuffered, so the list is complete even if the run is cut short
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(err) = file.write_all(&entry) {
+            error!("Failed to write {} to the --failed-out file: {}", path.display(), err);
+        }
+    }
+}
+
+/// The files evicted so far, kept in the `--state` file as a JSON list of paths so a later
+/// run can tell which of them were downloaded again.
+struct StateFile {
+    path: PathBuf,
+    /// Evicted by earlier runs
+    previous: HashSet<PathBuf>,
+    /// Evicted in this run
+    evicted: Mutex<Vec<PathBuf>>,
+    redownloaded: Mutex<Vec<(PathBuf, u64)>>,
+}
+
+/// Files an earlier run evicted that were found downloaded again.
+#[derive(Serialize)]
+struct Redownloaded {
+    files: usize,
+    bytes: u64,
+    paths: Vec<String>,
+}
+
+impl StateFile {
+    /// Read the files recorded in `path`; a file that doesn't exist yet records none.
+    fn open(path: &Path) -> io::Result<Self> {
+        let previous: Vec<PathBuf> = match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            previous: previous.into_iter().collect(),
+            evicted: Mutex::new(Vec::new()),
+            redownloaded: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn record(&self, event: &FileEvent) {
+        // Whatever is still downloaded at the end is found by `redownloaded` instead
+        if event.outcome != Outcome::Success {
+            return;
+        }
+        // Absolute, so runs from another working directory still match
+        let path = std::fs::canonicalize(event.path).unwrap_or_else(|_| event.path.to_path_buf());
+        if self.previous.contains(&path) {
+            let mut redownloaded = self.redownloaded.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            redownloaded.push((path.clone(), event.size_bytes));
+        }
+        self.evicted.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(path);
+    }
+
+    /// The earlier files found downloaded again: the ones this run evicted once more, plus
+    /// the ones under `targets` that `downloaded` still finds downloaded, whether or not
+    /// the filters selected them.
+    fn redownloaded(&self, targets: &[PathBuf], downloaded: impl Fn(&Path) -> bool) -> Redownloaded {
+        let mut files = self.redownloaded.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+        let targets: Vec<PathBuf> = targets
+            .iter()
+            .map(|target| std::fs::canonicalize(target).unwrap_or_else(|_| target.clone()))
+            .collect();
+        for path in &self.previous {
+            if !targets.iter().any(|target| path.starts_with(target)) || !downloaded(path) {
+                continue;
+            }
+            if let Ok(metadata) = path.symlink_metadata() {
+                files.push((path.clone(), metadata.len()));
+            }
+        }
+        files.sort();
+        files.dedup_by(|a, b| a.0 == b.0);
+        Redownloaded {
+            files: files.len(),
+            bytes: files.iter().map(|&(_, size)| size).sum(),
+            paths: files.iter().map(|(path, _)| path.display().to_string()).collect(),
+        }
+    }
+
+    /// Write the earlier files that still exist plus the ones evicted in this run.
+    fn save(&self) -> io::Result<()> {
+        let mut files: Vec<&PathBuf> = self.previous.iter().filter(|path| path.symlink_metadata().is_ok()).collect();
+        let evicted = self.evicted.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        files.extend(evicted.iter().filter(|path| !self.previous.contains(*path)));
+        files.sort();
+        // Replaced in one step, so an interrupted write leaves the old state intact
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(&files)?)?;
+        std::fs::rename(&partial, &self.path)
+    }
+}
+
+/// The raw bytes of `path`, so names that aren't valid UTF-8 survive a round trip.
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+/// One line of `--format jsonl` output.
+#[derive(Serialize)]
+struct FileLine<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    path: String,
+    size_bytes: u64,
+    outcome: Outcome,
+    exit_code: Option<i32>,
+    error: Option<&'a str>,
+    duration_ms: Option<u128>,
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+}
+
+/// Tags a JSON Lines record with its `type`.
+#[derive(Serialize)]
+struct Tagged<'a, T> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(flatten)]
+    record: &'a T,
+}
+
+/// Confirmation, progress bar and per-file output of the command line run.
+struct Console {
+    csv: Option<CsvLog>,
+    failed_out: Option<FailedOut>,
+    state: Option<StateFile>,
+    jsonl: bool,
+    /// Paths of evicted files go to stdout, ended by this delimiter
+    print_paths: Option<u8>,
+    dry_run: bool,
+    units: Units,
+    /// Ask before evicting anything
+    confirm: bool,
+    /// Ask about each file, until answered with `a`
+    ask: AtomicBool,
+    /// Where the files come from, for the prompt: `under <directories>` or `listed in <file>`
+    source: String,
+    /// Show a progress bar once the files to evict are known
+    show_progress: bool,
+    progress: OnceLock<ProgressBar>,
+    /// Print the running totals this often
+    stats_interval: Option<Duration>,
+    /// Print the running totals after this many processed files
+    progress_every: Option<usize>,
+    /// Wakes the `--stats-interval` thread up to exit once dropped
+    heartbeat: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,
+    counters: Arc<Counters>,
+    stop: Arc<AtomicBool>,
+    ctrlc: Once,
+}
+
+/// Running totals of the processed files, shared with the `--stats-interval` thread.
+#[derive(Default)]
+struct Counters {
+    /// Set once the selected files are known; only those count as processed
+    started: OnceLock<Instant>,
+    files: AtomicUsize,
+    /// A streaming run is still walking, so `files` isn't the total yet
+    walking: AtomicBool,
+    processed: AtomicUsize,
+    failed: AtomicUsize,
+    freed: AtomicU64,
+}
+
+impl Counters {
+    /// The number of selected files, unknown while a streaming walk is going on.
+    fn total(&self) -> Option<usize> {
+        (!self.walking.load(Ordering::Relaxed)).then(|| self.files.load(Ordering::Relaxed))
+    }
+
+    /// One-line snapshot such as `progress: 12,340/98,000 files, 48.20 GiB evicted, 310 failed, 2.1 files/s`.
+    fn status_line(&self, units: Units) -> String {
+        let elapsed = self.started.get().map_or(Duration::ZERO, Instant::elapsed);
+        let processed = self.processed.load(Ordering::Relaxed);
+        format!(
+            "progress: {}{} files, {} evicted, {} failed, {:.1} files/s",
+            format_count(processed),
+            self.total().map_or(String::new(), |files| format!("/{}", format_count(files))),
+            format_bytes(self.freed.load(Ordering::Relaxed), units),
+            format_count(self.failed.load(Ordering::Relaxed)),
+            processed as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+        )
+    }
+}
+
+impl Console {
+    /// Stop gracefully on Ctrl+C from now on.
+    fn handle_ctrlc(&self) {
+        self.ctrlc.call_once(|| {
+            let stop = Arc::clone(&self.stop);
+            let interrupted = AtomicBool::new(false);
+            ctrlc::set_handler(move || {
+                // A second Ctrl+C is the way out of a hung brctl call
+                if interrupted.swap(true, Ordering::Relaxed) {
+                    FORCE_QUITTING.store(true, Ordering::SeqCst);
+                    error!("\nReceived Ctrl+C again, force quitting");
+                    evict_icloud::kill_running_brctl();
+                    restore_terminal();
+                    log::logger().flush();
+                    std::process::exit(EXIT_FORCE_QUIT);
                 }
-            };
+                warn!("\nReceived Ctrl+C, stopping gracefully... (press Ctrl+C again to force quit)");
+                stop.store(true, Ordering::Relaxed);
+                log::logger().flush();
+            })
+            .expect("Error setting Ctrl+C handler");
+        });
+    }
+
+    /// Stop the `--stats-interval` thread once the run is over.
+    fn finish(&self) {
+        let heartbeat = self.heartbeat.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some((sender, handle)) = heartbeat {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
 
-            stats.3.fetch_add(file_size, Ordering::Relaxed);
+    fn flush(&self) {
+        if let Some(csv) = &self.csv {
+            csv.flush();
+        }
+    }
 
-            if cli.dry_run {
-                println!("[dry-run] Would evict: {} ({})", file_path.display(), format_bytes(file_size));
-                stats.1.fetch_add(1, Ordering::Relaxed);
-                stats.4.fetch_add(file_size, Ordering::Relaxed);
-                return;
+    /// Confirm and set up the progress output, with the selected files unless streaming.
+    fn begin(&self, selected: Option<(usize, u64)>) -> bool {
+        // Later passes of --watch were already agreed to
+        let first = self.counters.started.get().is_none();
+        if self.confirm && first {
+            if !io::stdin().is_terminal() {
+                Cli::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "stdin is not a terminal, so the eviction can't be confirmed; pass --yes to proceed anyway",
+                    )
+                    .print()
+                    .ok();
+                log::logger().flush();
+                std::process::exit(EXIT_USAGE);
             }
+            let question = match selected {
+                Some((files, bytes)) => format!(
+                    "About to evict {} files totaling {} {}.",
+                    format_count(files),
+                    format_bytes(bytes, self.units),
+                    self.source
+                ),
+                None => format!("About to evict the selected files {} as they are found.", self.source),
+            };
+            if !confirm(&question) {
+                return false;
+            }
+        }
+
+        if let (true, Some((files, bytes))) = (self.dry_run, selected) {
+            info!("Would evict {} files totaling {}", files, format_bytes(bytes, self.units));
+        }
 
-            match Command::new("brctl")
-                .args(["evict", file_path.to_str().unwrap()])
-                .status()
-            {
-                Ok(status) if status.success() => {
-                    println!("evicted content of '{}' ({})", file_path.display(), format_bytes(file_size));
-                    stats.1.fetch_add(1, Ordering::Relaxed);
-                    stats.4.fetch_add(file_size, Ordering::Relaxed);
+        // Installed only now so Ctrl+C still aborts the prompt and the walk right away
+        self.handle_ctrlc();
+
+        let (files, bytes) = selected.unwrap_or_default();
+        self.counters.files.fetch_add(files, Ordering::Relaxed);
+        self.counters.walking.store(selected.is_none(), Ordering::Relaxed);
+        if !first {
+            return true;
+        }
+        let _ = self.counters.started.set(Instant::now());
+        if let Some(interval) = self.stats_interval {
+            let (sender, receiver) = mpsc::channel::<()>();
+            let counters = Arc::clone(&self.counters);
+            let units = self.units;
+            let handle = thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    Logger::status(&counters.status_line(units));
                 }
-                Ok(status) => {
-                    eprintln!(
-                        "Failed evicting {} ({}) - brctl command failed (exit code: {:?})",
-                        file_path.display(),
-                        format_bytes(file_size),
-                        status.code()
-                    );
-                    stats.2.fetch_add(1, Ordering::Relaxed);
-                    stats.5.fetch_add(file_size, Ordering::Relaxed);
+            });
+            *self.heartbeat.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some((sender, handle));
+        }
+        if self.show_progress {
+            // Track bytes rather than files so throughput and ETA reflect the actual work
+            let template = match selected {
+                Some(_) => BAR_TEMPLATE,
+                // Until the walk is over there is no total to fill a bar up to
+                None => "{spinner} {done} ({rate}), {msg}",
+            };
+            let progress = ProgressBar::new(bytes)
+                .with_style(progress_style(template, self.units))
+                .with_message(match selected {
+                    Some(_) => format!("0/{} files, {} freed", files, format_bytes(0, self.units)),
+                    None => format!("0 files, {} freed", format_bytes(0, self.units)),
+                });
+            if selected.is_none() {
+                progress.enable_steady_tick(Duration::from_millis(100));
+            }
+            Logger::set_progress(Some(progress.clone()));
+            let _ = self.progress.set(progress);
+        }
+        true
+    }
+}
+
+/// How the progress bar looks once the total is known.
+const BAR_TEMPLATE: &str = "{bar:40} {done}/{total} ({rate}), {msg}, ETA {eta}";
+
+/// The progress bar's look; sizes are in the same units as the rest of the output.
+fn progress_style(template: &str, units: Units) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("Invalid progress bar template")
+        .with_key("done", move |state: &ProgressState, w: &mut dyn fmt::Write| {
+            let _ = w.write_str(&format_bytes(state.pos(), units));
+        })
+        .with_key("total", move |state: &ProgressState, w: &mut dyn fmt::Write| {
+            let _ = w.write_str(&format_bytes(state.len().unwrap_or(0), units));
+        })
+        .with_key("rate", move |state: &ProgressState, w: &mut dyn fmt::Write| {
+            let _ = write!(w, "{}/s", format_bytes(state.per_sec() as u64, units));
+        })
+}
+
+impl Observer for Console {
+    fn starting(&self, files: usize, bytes: u64) -> bool {
+        self.begin(Some((files, bytes)))
+    }
+
+    fn streaming(&self) -> bool {
+        self.begin(None)
+    }
+
+    fn walk_done(&self, files: usize, bytes: u64) {
+        let counters = &self.counters;
+        counters.files.fetch_add(files, Ordering::Relaxed);
+        counters.walking.store(false, Ordering::Relaxed);
+        if let Some(progress) = self.progress.get() {
+            // Whatever was already evicted counts towards the now known total
+            progress.set_length(bytes);
+            progress.disable_steady_tick();
+            progress.set_style(progress_style(BAR_TEMPLATE, self.units));
+        }
+    }
+
+    fn decide(&self, path: &Path, bytes: u64) -> Decision {
+        if !self.ask.load(Ordering::Relaxed) {
+            return Decision::Evict;
+        }
+        loop {
+            eprint!("Evict {} ({})? [y/n/a/q] ", path.display(), format_bytes(bytes, self.units));
+            let _ = io::stderr().flush();
+            match read_key() {
+                Some('y') => return Decision::Evict,
+                Some('n') => return Decision::Skip,
+                Some('a') => {
+                    self.ask.store(false, Ordering::Relaxed);
+                    return Decision::Evict;
                 }
-                Err(err) => {
-                    eprintln!("Failed evicting {} ({}) - brctl command error: {}", file_path.display(), format_bytes(file_size), err);
-                    stats.2.fetch_add(1, Ordering::Relaxed);
-                    stats.5.fetch_add(file_size, Ordering::Relaxed);
+                Some('q') | None => return Decision::Stop,
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn file_done(&self, event: &FileEvent) {
+        if let Some(csv) = &self.csv {
+            csv.record(event);
+        }
+        if let (Some(failed_out), Outcome::Failed) = (&self.failed_out, event.outcome) {
+            failed_out.record(event.path);
+        }
+        if let Some(state) = &self.state {
+            state.record(event);
+        }
+        if let (Some(delimiter), Outcome::Success | Outcome::WouldEvict) = (self.print_paths, event.outcome) {
+            let mut entry = path_bytes(event.path);
+            entry.push(delimiter);
+            // One write per path keeps parallel workers from interleaving
+            let _ = io::stdout().lock().write_all(&entry);
+        }
+        if self.jsonl {
+            let line = FileLine {
+                kind: "file",
+                path: event.path.display().to_string(),
+                size_bytes: event.size_bytes,
+                outcome: event.outcome,
+                exit_code: event.exit_code,
+                error: event.error,
+                duration_ms: event.duration.map(|duration| duration.as_millis()),
+                timestamp: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+            };
+            let line = serde_json::to_string(&line).expect("Failed to serialize file event");
+            // Holding the lock for the whole line keeps parallel workers from interleaving,
+            // and stdout is flushed at every newline even when redirected to a file
+            let _ = writeln!(io::stdout().lock(), "{}", line);
+        }
+
+        let counters = &self.counters;
+        let mut freed = counters.freed.load(Ordering::Relaxed);
+        match event.outcome {
+            Outcome::Success | Outcome::WouldEvict => {
+                freed = counters.freed.fetch_add(event.size_bytes, Ordering::Relaxed) + event.size_bytes;
+            }
+            Outcome::Failed => {
+                counters.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            Outcome::Skipped | Outcome::NoOp => {}
+        }
+        if counters.started.get().is_none() {
+            return;
+        }
+        let processed = counters.processed.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(every) = self.progress_every {
+            // Based on this worker's own increment, so each multiple is printed once
+            if processed.checked_rem(every) == Some(0) {
+                Logger::status(&counters.status_line(self.units));
+            }
+        }
+        if let Some(progress) = self.progress.get() {
+            progress.inc(event.size_bytes);
+            progress.set_message(format!(
+                "{}{} files, {} freed",
+                processed,
+                counters.total().map_or(String::new(), |files| format!("/{}", files)),
+                format_bytes(freed, self.units)
+            ));
+        }
+    }
+}
+
+/// Settings of the run that are reported back in the summary.
+struct SummaryOptions {
+    /// The --files-from source, in place of the walked directories
+    file_list: Option<PathBuf>,
+    /// Compared against the --state file
+    redownloaded: Option<Redownloaded>,
+    dry_run: bool,
+    count: bool,
+    /// Every extension in the human summary, not just the top ten
+    by_extension: bool,
+    units: Units,
+    format: Format,
+    /// Color the human summary
+    color: bool,
+    started: Instant,
+}
+
+/// Snapshot of a run that both the human and the JSON output are rendered from.
+#[derive(Serialize)]
+struct Summary<'a> {
+    directories: Vec<String>,
+    dry_run: bool,
+    elapsed_seconds: f64,
+    /// Successful files and bytes per second of wall-clock time
+    files_per_second: f64,
+    bytes_per_second: f64,
+    #[serde(flatten)]
+    report: &'a EvictReport,
+    /// Only with --state
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redownloaded: Option<&'a Redownloaded>,
+    /// Only the totals of a --count scan
+    #[serde(skip)]
+    count: bool,
+    #[serde(skip)]
+    all_extensions: bool,
+    #[serde(skip)]
+    units: Units,
+}
+
+impl Summary<'_> {
+    fn write_human(&self, out: &mut impl Write, color: bool) -> io::Result<()> {
+        let report = self.report;
+        if self.count {
+            writeln!(out, "\n{}", paint("=== Evictable ===", BOLD, color))?;
+            writeln!(
+                out,
+                "Files: {} ({})",
+                report.successful.files,
+                format_bytes(report.successful.bytes, self.units)
+            )?;
+            self.write_breakdowns(out)?;
+            self.write_skipped(out, color)?;
+            return writeln!(out, "Scanned in {}", format_elapsed(self.elapsed_seconds));
+        }
+
+        let heading = if report.interrupted { "=== Summary (interrupted) ===" } else { "=== Summary ===" };
+        writeln!(out, "\n{}", paint(heading, BOLD, color))?;
+        writeln!(
+            out,
+            "Files attempted: {} ({})",
+            report.attempted.files,
+            format_bytes(report.attempted.bytes, self.units)
+        )?;
+        let successful = format!(
+            "Files successful: {} ({})",
+            report.successful.files,
+            format_bytes(report.successful.bytes, self.units)
+        );
+        writeln!(out, "{}", paint(&successful, GREEN, color && report.successful.files > 0))?;
+        let failed = format!(
+            "Files failed: {} ({})",
+            report.failed.files,
+            format_bytes(report.failed.bytes, self.units)
+        );
+        writeln!(out, "{}", paint(&failed, RED, color && report.failed.files > 0))?;
+        if report.timed_out.files > 0 {
+            writeln!(
+                out,
+                "  of which timed out: {} ({})",
+                report.timed_out.files,
+                format_bytes(report.timed_out.bytes, self.units)
+            )?;
+        }
+        if report.unattempted.files > 0 {
+            writeln!(
+                out,
+                "Files never attempted: {} ({})",
+                report.unattempted.files,
+                format_bytes(report.unattempted.bytes, self.units)
+            )?;
+        }
+        if report.retried.files > 0 {
+            writeln!(
+                out,
+                "Files successful after retrying: {} ({})",
+                report.retried.files,
+                format_bytes(report.retried.bytes, self.units)
+            )?;
+        }
+        if report.no_op.files > 0 {
+            let no_op = format!(
+                "Files still downloaded after brctl: {} ({})",
+                report.no_op.files,
+                format_bytes(report.no_op.bytes, self.units)
+            );
+            writeln!(out, "{}", paint(&no_op, YELLOW, color))?;
+        }
+        for totals in &report.per_directory {
+            writeln!(
+                out,
+                "  {}: {} successful ({}), {} failed ({})",
+                totals.directory,
+                totals.successful.files,
+                format_bytes(totals.successful.bytes, self.units),
+                totals.failed.files,
+                format_bytes(totals.failed.bytes, self.units)
+            )?;
+        }
+        self.write_breakdowns(out)?;
+        if let Some(redownloaded) = self.redownloaded.filter(|redownloaded| redownloaded.files > 0) {
+            writeln!(
+                out,
+                "Downloaded again since the last run: {} ({})",
+                redownloaded.files,
+                format_bytes(redownloaded.bytes, self.units)
+            )?;
+            for path in redownloaded.paths.iter().take(10) {
+                writeln!(out, "  {}", path)?;
+            }
+        }
+        if let (Some(target), Some(met)) = (report.free_target_bytes, report.free_target_met) {
+            writeln!(
+                out,
+                "Space freed: {} of {} requested (target {})",
+                format_bytes(report.successful.bytes, self.units),
+                format_bytes(target, self.units),
+                if met { "met" } else { "not met" }
+            )?;
+        }
+
+        if !report.failures.is_empty() {
+            let mut reasons: Vec<(&str, usize)> = Vec::new();
+            for failure in &report.failures {
+                match reasons.iter_mut().find(|(reason, _)| *reason == failure.reason) {
+                    Some((_, files)) => *files += 1,
+                    None => reasons.push((&failure.reason, 1)),
                 }
             }
+            reasons.sort_by(|(a, a_files), (b, b_files)| b_files.cmp(a_files).then_with(|| a.cmp(b)));
+            writeln!(out, "Failure reasons:")?;
+            for (reason, files) in reasons.iter().take(10) {
+                writeln!(out, "  {} files: {}", files, paint(reason, RED, color))?;
+            }
+        }
+        self.write_skipped(out, color)?;
+        writeln!(
+            out,
+            "Elapsed: {} ({:.1} files/s, {}/s)",
+            format_elapsed(self.elapsed_seconds),
+            self.files_per_second,
+            format_bytes(self.bytes_per_second as u64, self.units)
+        )?;
+
+        writeln!(out, "Eviction complete.")
+    }
+
+    /// The ten subdirectories and extensions that freed the most (every extension with
+    /// --by-extension); the JSON output has all of them.
+    fn write_breakdowns(&self, out: &mut impl Write) -> io::Result<()> {
+        let report = self.report;
+        if !report.by_directory.is_empty() {
+            writeln!(out, "By directory:")?;
+            for totals in report.by_directory.iter().take(10) {
+                writeln!(out, "  {}: {} ({})", totals.directory, totals.files, format_bytes(totals.bytes, self.units))?;
+            }
+        }
+        if !report.by_extension.is_empty() {
+            writeln!(out, "By extension:")?;
+            let shown = if self.all_extensions { report.by_extension.len() } else { 10 };
+            for totals in report.by_extension.iter().take(shown) {
+                writeln!(out, "  {}: {} ({})", totals.extension, totals.files, format_bytes(totals.bytes, self.units))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_skipped(&self, out: &mut impl Write, color: bool) -> io::Result<()> {
+        let report = self.report;
+        for skipped in report.skipped.iter().filter(|skipped| skipped.files > 0) {
+            let line = format!(
+                "Files skipped ({}): {} ({})",
+                skipped.description,
+                skipped.files,
+                format_bytes(skipped.bytes, self.units)
+            );
+            writeln!(out, "{}", paint(&line, YELLOW, color))?;
+        }
+        for skipped in report.skipped_directories.iter().filter(|skipped| skipped.directories > 0) {
+            writeln!(out, "Directories skipped ({}): {}", skipped.description, skipped.directories)?;
+        }
+        if report.walk_errors > 0 {
+            writeln!(out, "Walk errors: {}", report.walk_errors)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Summary<'a> {
+    fn new(report: &'a EvictReport, options: &'a SummaryOptions) -> Self {
+        // Never zero, so the rates stay finite
+        let elapsed = options.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        Summary {
+            directories: match &options.file_list {
+                Some(source) => vec![source.display().to_string()],
+                None => report
+                    .directories
+                    .iter()
+                    .map(|directory| directory.display().to_string())
+                    .collect(),
+            },
+            dry_run: options.dry_run,
+            count: options.count,
+            redownloaded: options.redownloaded.as_ref(),
+            all_extensions: options.by_extension,
+            units: options.units,
+            elapsed_seconds: elapsed,
+            files_per_second: report.successful.files as f64 / elapsed,
+            bytes_per_second: report.successful.bytes as f64 / elapsed,
+            report,
+        }
+    }
+}
+
+fn print_summary(report: &EvictReport, options: &SummaryOptions) {
+    let summary = Summary::new(report, options);
+    match options.format {
+        Format::Human => {
+            let _ = summary.write_human(&mut io::stderr().lock(), options.color);
+        }
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&summary).expect("Failed to serialize summary")
+        ),
+        Format::Jsonl => println!(
+            "{}",
+            serde_json::to_string(&Tagged {
+                kind: "summary",
+                record: &summary,
+            })
+            .expect("Failed to serialize summary")
+        ),
+    }
+}
+
+/// Post a macOS notification with the headline numbers of `report` through `osascript`.
+fn notify(report: &EvictReport, dry_run: bool, units: Units) {
+    let mut message = format!(
+        "{} {} files ({})",
+        if dry_run { "Would evict" } else { "Evicted" },
+        report.successful.files,
+        format_bytes(report.successful.bytes, units)
+    );
+    if report.failed.files > 0 {
+        message.push_str(&format!(", {} failed", report.failed.files));
+    }
+    let title = if report.interrupted { "evict-icloud interrupted" } else { "evict-icloud finished" };
+    // AppleScript string literals only need quotes and backslashes escaped
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    let script = format!("display notification {} with title {}", quote(&message), quote(title));
+    match std::process::Command::new("osascript").arg("-e").arg(script).output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => warn!(
+            "Warning: could not post the notification: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(err) => warn!("Warning: could not post the notification: {}", err),
+    }
+}
+
+/// Exit status when at least one file could not be evicted.
+const EXIT_FAILED: i32 = 1;
+/// Exit status for unusable arguments or environment, as clap uses for its errors.
+const EXIT_USAGE: i32 = 2;
+/// Exit status after Ctrl+C, following the shell convention of 128 + SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+/// Exit status after a second Ctrl+C, without waiting for running `brctl` calls.
+const EXIT_FORCE_QUIT: i32 = 131;
+
+/// Set by the Ctrl+C handler once it is force quitting.
+static FORCE_QUITTING: AtomicBool = AtomicBool::new(false);
+
+fn main() {
+    // Enable standard backtrace via environment variable if desired.
+
+    let started = Instant::now();
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    if let Some(name) = profile::requested(&args) {
+        // What the command line sets itself takes precedence over the profile
+        let explicit = Cli::command().ignore_errors(true).try_get_matches_from(&args).ok();
+        let given = |id: &str| {
+            explicit
+                .as_ref()
+                .is_some_and(|matches| matches.value_source(id) == Some(ValueSource::CommandLine))
+        };
+        let saved = profile::default_path()
+            .ok_or_else(|| "can't locate the config file: neither XDG_CONFIG_HOME nor HOME is set".to_string())
+            .and_then(|path| profile::load(&path, &name, &Cli::command(), &given))
+            .unwrap_or_else(|err| Cli::command().error(ErrorKind::InvalidValue, err).exit());
+        args.splice(1..1, saved);
+    }
+    let mut cli = Cli::parse_from(args);
+    if cli.json {
+        cli.format = Format::Json;
+    }
+    cli.dry_run |= cli.count;
+
+    // Dry runs exist to list what would be evicted, so show that by default
+    Logger::init(
+        match (cli.quiet, cli.verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) if cli.dry_run && !cli.count => LevelFilter::Info,
+            (false, 0) => LevelFilter::Warn,
+            (false, 1) => LevelFilter::Info,
+            (false, 2) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        },
+        cli.color.enabled(io::stderr().is_terminal()),
+    );
+
+    if cli.interactive && !io::stdin().is_terminal() {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--interactive needs stdin to be a terminal to read the answers from",
+            )
+            .exit();
+    }
+
+    if cli.print_paths && cli.format != Format::Human {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--print-paths can't be combined with --format json or jsonl, which write to stdout too",
+            )
+            .exit();
+    }
+
+    if let (Some(min), Some(max)) = (cli.min_size, cli.max_size) {
+        if max < min {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "--max-size ({}) must not be smaller than --min-size ({})",
+                        format_bytes(max, cli.units),
+                        format_bytes(min, cli.units)
+                    ),
+                )
+                .exit();
+        }
+    }
+
+    if let (Some(min), Some(max)) = (cli.min_depth, cli.max_depth) {
+        if max < min {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("--max-depth ({}) must not be smaller than --min-depth ({})", max, min),
+                )
+                .exit();
+        }
+    }
+
+    // brctl evicts everything below the target, protected files included
+    if cli.recursive_evict {
+        let mut missing = Vec::new();
+        if !cli.no_ignore {
+            missing.push("--no-ignore");
+        }
+        if !cli.keep_recent.is_zero() {
+            missing.push("--keep-recent 0");
+        }
+        if !cli.evict_open_files {
+            missing.push("--evict-open-files");
+        }
+        if let Some(last) = missing.pop() {
+            let missing = if missing.is_empty() {
+                last.to_string()
+            } else {
+                format!("{} and {}", missing.join(", "), last)
+            };
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "--recursive-evict can't honor ignore files, --keep-recent or the open-file check; \
+                         pass {} to evict protected files too",
+                        missing
+                    ),
+                )
+                .exit();
+        }
+    }
+
+    // Listed files are checked one by one while collecting
+    for directory in &cli.directories {
+        if let Err(err) = std::fs::metadata(directory) {
+            Cli::command()
+                .error(ErrorKind::Io, format!("cannot access {}: {}", directory.display(), err))
+                .exit();
+        }
+        if !cli.allow_non_icloud && !is_icloud_path(directory) {
+            Cli::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "{} is not inside iCloud Drive; pass --allow-non-icloud to run anyway",
+                        directory.display()
+                    ),
+                )
+                .exit();
+        }
+    }
+
+    if !cfg!(target_os = "macos") {
+        warn!("Warning: evict-icloud only works on macOS; brctl and iCloud placeholders are not available here");
+    }
+
+    let evictor = evictor(cli.backend).unwrap_or_else(|| {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--backend native needs a macOS build with the native feature (cargo build --release --features native)",
+            )
+            .exit()
+    });
+
+    // Dry runs never call brctl, so they work anywhere; the native backend falls back to it
+    if !cli.dry_run && !brctl_available() {
+        error!("Error: `brctl` was not found in PATH or can't be run; it ships with macOS in /usr/bin");
+        std::process::exit(EXIT_USAGE);
+    }
+
+    if let Some(path) = &cli.log_file {
+        let log_file = RunLog::open(path, cli.log_file_truncate).unwrap_or_else(|err| {
+            Cli::command()
+                .error(ErrorKind::Io, format!("failed to open {}: {}", path.display(), err))
+                .exit()
         });
+        let args: Vec<String> = std::env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        log_file.write(&format!("Started: {}", args.join(" ")));
+        // Every evicted and failed file is logged at info, so the file gets at least that
+        Logger::set_log_file(log_file, log::max_level().max(LevelFilter::Info));
+    }
+
+    // Read the list before doing any work so a bad path fails fast
+    let list_source = cli.files_from.as_ref().or(cli.retry_from.as_ref());
+    let file_list = list_source.map(|source| {
+        read_file_list(source, cli.null).unwrap_or_else(|err| {
+            Cli::command()
+                .error(ErrorKind::Io, format!("failed to read {}: {}", source.display(), err))
+                .exit()
+        })
     });
 
-    print_summary(&stats);
-} 
\ No newline at end of file
+    let options = EvictOptions {
+        directories: cli.directories.clone(),
+        file_list,
+        skip_missing: cli.retry_from.is_some(),
+        // One at a time, so the prompts don't interleave with work
+        concurrency: if cli.interactive { 1 } else { cli.concurrency.unwrap_or_else(num_cpus::get) },
+        dry_run: cli.dry_run,
+        batch_size: if cli.interactive { 1 } else { cli.batch_size as usize },
+        follow_symlinks: cli.follow_symlinks,
+        skip_hidden: cli.skip_hidden,
+        max_depth: if cli.no_recurse { Some(1) } else { cli.max_depth.map(|depth| depth as usize) },
+        min_depth: cli.min_depth.map(|depth| depth as usize),
+        prune_dir: cli.prune_dir.clone(),
+        min_size: cli.min_size,
+        max_size: cli.max_size,
+        include: build_glob_set(&cli.include),
+        exclude: build_glob_set(&cli.exclude),
+        regex: cli.regex.clone(),
+        regex_full_path: cli.regex_full_path,
+        ext: split_extensions(&cli.ext),
+        exclude_ext: split_extensions(&cli.exclude_ext),
+        include_evicted: cli.include_evicted,
+        include_empty: cli.include_empty,
+        older_than: cli.older_than,
+        keep_recent: cli.keep_recent,
+        accessed_before: cli.accessed_before,
+        ignore_walk_errors: cli.ignore_walk_errors,
+        no_ignore: cli.no_ignore,
+        free: cli.free,
+        timeout: cli.timeout,
+        retries: cli.retries,
+        retry_delay: cli.retry_delay,
+        delay: cli.delay,
+        sort: if cli.largest_first { Some(SortOrder::SizeDesc) } else { cli.sort },
+        top: cli.top,
+        stream: cli.stream,
+        recursive_evict: cli.recursive_evict,
+        max_inflight: cli.max_inflight.map(|max| max as usize),
+        throttle: cli.throttle,
+        by_dir_depth: cli.by_dir_depth as usize,
+        // A --count scan has no use for the lsof snapshot
+        evict_open_files: cli.evict_open_files || cli.count,
+        rescan: false,
+        allow_non_icloud: cli.allow_non_icloud,
+        force: cli.force,
+        no_verify: cli.no_verify,
+        // Counting is a quick survey, so it skips the per-file download check
+        assume_downloaded: cli.count,
+        units: cli.units,
+        stop: Arc::default(),
+    };
+
+    // A progress bar replaces the per-file lines on interactive terminals, unless
+    // periodic status lines were asked for instead
+    let show_progress = cli.format == Format::Human
+        && cli.verbose == 0
+        && !cli.quiet
+        && cli.stats_interval == 0
+        && cli.progress_every.is_none()
+        && !cli.watch
+        && !cli.interactive
+        && !cli.count
+        && io::stderr().is_terminal();
+    let console = Console {
+        csv: cli.csv.as_deref().map(|path| {
+            CsvLog::create(path).unwrap_or_else(|err| {
+                Cli::command()
+                    .error(ErrorKind::Io, format!("failed to create {}: {}", path.display(), err))
+                    .exit()
+            })
+        }),
+        failed_out: cli.failed_out.as_deref().map(|path| {
+            FailedOut::create(path, cli.null).unwrap_or_else(|err| {
+                Cli::command()
+                    .error(ErrorKind::Io, format!("failed to create {}: {}", path.display(), err))
+                    .exit()
+            })
+        }),
+        state: cli.state.as_deref().map(|path| {
+            StateFile::open(path).unwrap_or_else(|err| {
+                Cli::command()
+                    .error(ErrorKind::Io, format!("failed to read {}: {}", path.display(), err))
+                    .exit()
+            })
+        }),
+        jsonl: cli.format == Format::Jsonl,
+        print_paths: cli.print_paths.then_some(if cli.null { b'\0' } else { b'\n' }),
+        dry_run: cli.dry_run,
+        units: cli.units,
+        // Every file is asked about in --interactive mode anyway
+        confirm: !cli.dry_run && !cli.yes && !cli.interactive,
+        ask: AtomicBool::new(cli.interactive),
+        source: match list_source {
+            Some(source) => format!("listed in {}", source.display()),
+            None => format!(
+                "under {}",
+                options
+                    .directories
+                    .iter()
+                    .map(|directory| directory.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        },
+        show_progress,
+        progress: OnceLock::new(),
+        stats_interval: (cli.stats_interval > 0).then(|| Duration::from_secs(cli.stats_interval)),
+        progress_every: match cli.progress_every {
+            Some(0) => None,
+            Some(every) => Some(every),
+            None if show_progress || cli.quiet || cli.count => None,
+            None => Some(100),
+        },
+        heartbeat: Mutex::new(None),
+        counters: Arc::default(),
+        stop: Arc::clone(&options.stop),
+        ctrlc: Once::new(),
+    };
+    let mut summary_options = SummaryOptions {
+        file_list: list_source.cloned(),
+        redownloaded: None,
+        dry_run: cli.dry_run,
+        count: cli.count,
+        by_extension: cli.by_extension,
+        units: cli.units,
+        format: cli.format,
+        color: cli.color.enabled(io::stderr().is_terminal()),
+        started,
+    };
+
+    let mut report = evict_icloud::run_with(&options, evictor, &console);
+    if cli.watch && !report.cancelled {
+        // A first pass that found nothing never got to install the handler
+        console.handle_ctrlc();
+        info!(
+            "Watching for files idle for {}, rescanning every {}; press Ctrl+C to stop",
+            format_elapsed(cli.idle.as_secs_f64()),
+            format_elapsed(cli.rescan_every.as_secs_f64())
+        );
+        while !report.interrupted {
+            // An interval too long to add to the clock waits for Ctrl+C
+            let deadline = Instant::now().checked_add(cli.rescan_every);
+            let rescan_due = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            while !options.stop.load(Ordering::Relaxed) && !rescan_due() {
+                thread::sleep(Duration::from_millis(100));
+            }
+            if options.stop.load(Ordering::Relaxed) {
+                report.interrupted = true;
+                break;
+            }
+
+            // Files are only evicted again once nothing has touched them for --idle
+            let idle_since = SystemTime::now().checked_sub(cli.idle).unwrap_or(SystemTime::UNIX_EPOCH);
+            let pass = EvictOptions {
+                rescan: true,
+                accessed_before: Some(options.accessed_before.map_or(idle_since, |cutoff| cutoff.min(idle_since))),
+                ..options.clone()
+            };
+            report.merge(evict_icloud::run_with(&pass, evictor, &console));
+        }
+    }
+    // Killing brctl lets the run end, but the handler is the one to exit then
+    while FORCE_QUITTING.load(Ordering::SeqCst) {
+        thread::park();
+    }
+    console.finish();
+
+    if let Some(progress) = console.progress.get() {
+        if report.interrupted {
+            progress.abandon();
+        } else {
+            progress.finish();
+        }
+    }
+    console.flush();
+    if let Some(state) = &console.state {
+        let targets: Vec<PathBuf> = match &options.file_list {
+            Some(files) => files.iter().map(|(_, path)| path.clone()).collect(),
+            None => options.directories.clone(),
+        };
+        let downloaded = |path: &Path| matches!(is_materialized(path), Ok(true));
+        summary_options.redownloaded = Some(state.redownloaded(&targets, downloaded));
+        // A dry run evicted nothing to remember
+        if !cli.dry_run && !report.cancelled {
+            if let Err(err) = state.save() {
+                error!("Failed to write the --state file {}: {}", state.path.display(), err);
+            }
+        }
+    }
+    if report.cancelled {
+        if let Some(log_file) = Logger::log_file() {
+            log_file.write("Aborted, nothing was evicted");
+        }
+        log::logger().flush();
+        eprintln!("Aborted, nothing was evicted");
+        return;
+    }
+    if let Some(log_file) = Logger::log_file() {
+        let mut summary = Vec::new();
+        let _ = Summary::new(&report, &summary_options).write_human(&mut summary, false);
+        log_file.write(String::from_utf8_lossy(&summary).trim_start());
+    }
+    log::logger().flush();
+
+    // A run that found nothing only gets a summary when there is something in it
+    let skipped_any = report.skipped.iter().any(|skipped| skipped.files > 0)
+        || report.skipped_directories.iter().any(|skipped| skipped.directories > 0);
+    if report.selected.files > 0
+        || cli.format != Format::Human
+        || report.failed.files > 0
+        || report.walk_errors > 0
+        || skipped_any
+    {
+        print_summary(&report, &summary_options);
+    }
+    if cli.notify {
+        notify(&report, cli.dry_run, cli.units);
+        log::logger().flush();
+    }
+
+    if report.interrupted {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if report.failed.files > 0 {
+        std::process::exit(EXIT_FAILED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        value.as_object().unwrap().keys().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn summary_json_keys() {
+        let dir = std::env::temp_dir().join(format!("evict-icloud-summary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "contents").unwrap();
+        let mut report = evict_icloud::run(&EvictOptions {
+            directories: vec![dir.clone()],
+            dry_run: true,
+            keep_recent: Duration::ZERO,
+            allow_non_icloud: true,
+            ..EvictOptions::default()
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        report.failures.push(evict_icloud::Failure {
+            path: "b.txt".to_string(),
+            error: "not a cloud file".to_string(),
+            reason: "not a cloud file".to_string(),
+        });
+        let options = SummaryOptions {
+            file_list: None,
+            redownloaded: Some(Redownloaded {
+                files: 0,
+                bytes: 0,
+                paths: Vec::new(),
+            }),
+            dry_run: true,
+            count: false,
+            by_extension: false,
+            units: Units::Binary,
+            format: Format::Json,
+            color: false,
+            started: Instant::now(),
+        };
+
+        let summary = serde_json::to_value(Summary::new(&report, &options)).unwrap();
+        assert_eq!(
+            keys(&summary),
+            [
+                "attempted",
+                "by_directory",
+                "by_extension",
+                "bytes_per_second",
+                "directories",
+                "dry_run",
+                "elapsed_seconds",
+                "failed",
+                "failures",
+                "files_per_second",
+                "free_target_bytes",
+                "free_target_met",
+                "interrupted",
+                "no_op",
+                "redownloaded",
+                "retried",
+                "skipped",
+                "skipped_directories",
+                "successful",
+                "timed_out",
+                "unattempted",
+                "walk_errors",
+            ]
+        );
+        for count in ["attempted", "successful", "failed", "timed_out", "no_op", "retried", "unattempted"] {
+            assert_eq!(keys(&summary[count]), ["bytes", "files"], "{}", count);
+        }
+        assert_eq!(keys(&summary["by_directory"][0]), ["bytes", "directory", "files"]);
+        assert_eq!(keys(&summary["by_extension"][0]), ["bytes", "extension", "files"]);
+        assert_eq!(keys(&summary["skipped"][0]), ["bytes", "files", "reason"]);
+        assert_eq!(keys(&summary["skipped_directories"][0]), ["directories", "reason"]);
+        assert_eq!(keys(&summary["failures"][0]), ["error", "path", "reason"]);
+        assert_eq!(keys(&summary["redownloaded"]), ["bytes", "files", "paths"]);
+    }
+
+    #[test]
+    fn parse_size_units() {
+        for (input, bytes) in [
+            ("0", 0),
+            ("512", 512),
+            ("512B", 512),
+            ("500K", 500 << 10),
+            ("1kb", 1 << 10),
+            ("1KiB", 1 << 10),
+            ("100MB", 100 << 20),
+            ("1.5GiB", 3 << 29),
+            ("2 tb", 2 << 40),
+            (" 0.5M ", 1 << 19),
+        ] {
+            assert_eq!(parse_size(input), Ok(bytes), "{}", input);
+        }
+        for (input, error) in [
+            ("10XB", "unknown size unit 'XB'"),
+            ("1.5PB", "unknown size unit 'PB'"),
+            ("MB", "expected a number"),
+            ("", "expected a number"),
+            ("1.2.3K", "expected a number"),
+            ("-5K", "expected a number"),
+        ] {
+            assert!(parse_size(input).unwrap_err().contains(error), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        for (input, duration) in [
+            ("500ms", Duration::from_millis(500)),
+            ("90s", Duration::from_secs(90)),
+            ("1.5m", Duration::from_secs(90)),
+            ("12h", Duration::from_secs(12 * 60 * 60)),
+            ("30D", Duration::from_secs(30 * 24 * 60 * 60)),
+            ("6w", Duration::from_secs(6 * 7 * 24 * 60 * 60)),
+            ("0", Duration::ZERO),
+        ] {
+            assert_eq!(parse_duration(input), Ok(duration), "{}", input);
+        }
+        for (input, error) in [
+            ("90", "missing unit"),
+            ("90y", "unknown duration unit 'y'"),
+            ("s", "expected a number"),
+            ("99999999999999999999999w", "out of range"),
+        ] {
+            assert!(parse_duration(input).unwrap_err().contains(error), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parse_rate_values() {
+        assert_eq!(parse_rate("2"), Ok(2.0));
+        assert_eq!(parse_rate(" 0.5 "), Ok(0.5));
+        for (input, error) in [
+            ("0", "expected a positive number"),
+            ("-1", "expected a positive number"),
+            ("inf", "expected a positive number"),
+            ("NaN", "expected a positive number"),
+            ("fast", "expected a positive number"),
+            ("1e-20", "too low"),
+        ] {
+            assert!(parse_rate(input).unwrap_err().contains(error), "{}", input);
+        }
+    }
+
+    #[test]
+    fn empty_extensions_only_when_asked_for() {
+        let ext = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["evict-icloud", "."].iter().chain(args)).unwrap();
+            split_extensions(&cli.ext)
+        };
+        assert_eq!(ext(&["--ext", "mov,MP4,.heic"]), ["mov", "mp4", "heic"]);
+        assert_eq!(ext(&["--ext", "mov", "--ext", ".JPG"]), ["mov", "jpg"]);
+        assert_eq!(ext(&["--ext", "mov,"]), ["mov"]);
+        assert_eq!(ext(&["--ext", ",mov,,"]), ["mov"]);
+        assert_eq!(ext(&["--ext", "mov", "--ext", ""]), ["mov", ""]);
+        assert_eq!(ext(&["--ext="]), [""]);
+        assert!(ext(&[]).is_empty());
+    }
+
+    #[test]
+    fn redownloads_outside_the_selection_are_reported() {
+        let dir = std::env::temp_dir().join(format!("evict-icloud-state-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        let dir = std::fs::canonicalize(dir).unwrap();
+        for name in ["evicted", "selected", "filtered", "gone"] {
+            std::fs::write(dir.join("target").join(name), name).unwrap();
+        }
+        std::fs::write(dir.join("elsewhere"), "elsewhere").unwrap();
+        let state_path = dir.join("state.json");
+        let mut remembered: Vec<PathBuf> =
+            ["evicted", "selected", "filtered", "gone"].iter().map(|name| dir.join("target").join(name)).collect();
+        remembered.push(dir.join("elsewhere"));
+        std::fs::write(&state_path, serde_json::to_vec(&remembered).unwrap()).unwrap();
+        std::fs::remove_file(dir.join("target").join("gone")).unwrap();
+
+        let state = StateFile::open(&state_path).unwrap();
+        let event = |name: &str, outcome| {
+            let path = dir.join("target").join(name);
+            state.record(&FileEvent {
+                path: &path,
+                size_bytes: name.len() as u64,
+                outcome,
+                exit_code: None,
+                error: None,
+                duration: Some(Duration::ZERO),
+            });
+        };
+        // Evicted again by this run, so no longer downloaded at the end
+        event("evicted", Outcome::Success);
+        event("selected", Outcome::Failed);
+        let still_downloaded = |path: &Path| !path.ends_with("evicted");
+        let redownloaded = state.redownloaded(&[dir.join("target")], still_downloaded);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let expected = ["evicted", "filtered", "selected"].map(|name| dir.join("target").join(name));
+        let expected = expected.map(|path| path.display().to_string());
+        assert_eq!(redownloaded.paths, expected);
+        assert_eq!(redownloaded.files, 3);
+        assert_eq!(redownloaded.bytes, 23);
+    }
+
+    #[test]
+    fn parse_date_checks_the_calendar() {
+        let date = |days: u64| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(days * 24 * 60 * 60));
+        assert_eq!(parse_date("1970-01-01"), date(0));
+        assert_eq!(parse_date("2024-01-01"), date(19_723));
+        assert_eq!(parse_date("2024-02-29"), date(19_782));
+        assert_eq!(parse_date("2000-02-29"), date(11_016));
+        assert_eq!(parse_date("2024-12-31"), date(20_088));
+
+        for input in ["2024-02-30", "2024-02-31", "2023-02-29", "2100-02-29", "2024-04-31", "2024-13-01", "2024-00-10"] {
+            assert_eq!(parse_date(input), None, "{}", input);
+        }
+        assert_eq!(parse_date("1969-12-31"), None);
+        assert_eq!(parse_date("2024-01"), None);
+        assert_eq!(parse_date("99999999999999-01-01"), None);
+        assert_eq!(parse_date("9223372036854775807-12-31"), None);
+    }
+
+    #[test]
+    fn parse_cutoff_takes_dates_and_ages() {
+        assert_eq!(parse_cutoff("2024-01-01"), Ok(parse_date("2024-01-01").unwrap()));
+        assert!(parse_cutoff("2024-02-31").unwrap_err().contains("invalid date"));
+        assert!(parse_cutoff("99999999999999-01-01").unwrap_err().contains("invalid date"));
+
+        // The wall clock isn't monotonic, so allow for it stepping either way
+        let ago = SystemTime::now().duration_since(parse_cutoff("90d").unwrap()).unwrap().as_secs_f64();
+        assert!((ago - 90.0 * 24.0 * 60.0 * 60.0).abs() < 60.0, "{}", ago);
+        assert!(parse_cutoff("90").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Eviction through `NSFileManager` inside the process, without spawning `brctl`.
+//!
+//! Talks to the Objective-C runtime directly, so the `native` feature needs no crates
+//! beyond std.
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+use log::{debug, trace};
+
+use crate::{BrctlEvictor, EvictOutcome, Evictor};
+
+type Id = *mut c_void;
+type Sel = *const c_void;
+/// `BOOL` is a `signed char` on x86_64 and a `bool` on arm64, one byte holding 0 or 1
+/// either way
+type Bool = i8;
+
+#[link(name = "objc")]
+extern "C" {
+    fn objc_getClass(name: *const c_char) -> Id;
+    fn sel_registerName(name: *const c_char) -> Sel;
+    fn objc_msgSend();
+    fn objc_autoreleasePoolPush() -> *mut c_void;
+    fn objc_autoreleasePoolPop(pool: *mut c_void);
+}
+
+#[link(name = "Foundation", kind = "framework")]
+extern "C" {}
+
+/// Evicts with `-[NSFileManager evictUbiquitousItemAtURL:error:]`, one file per call.
+///
+/// Errors that mean the same as a `brctl` failure, such as missing files or files outside
+/// iCloud, fail the file with a fixed message so the summary groups them; any other error
+/// hands the file to [`BrctlEvictor`], which also covers the timeout, as the API call
+/// itself can't be given one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NativeEvictor;
+
+impl Evictor for NativeEvictor {
+    fn evict(&self, path: &Path, timeout: Option<Duration>) -> io::Result<EvictOutcome> {
+        let error = match evict_ubiquitous_item(path) {
+            Ok(()) => {
+                trace!("NSFileManager evicted {}", path.display());
+                return Ok(EvictOutcome::Evicted);
+            }
+            Err(error) => error,
+        };
+        match error.failure() {
+            Some(failure) => Ok(EvictOutcome::Failed {
+                exit_code: None,
+                error: failure.to_string(),
+            }),
+            None => {
+                debug!("NSFileManager failed to evict {} ({}), trying brctl", path.display(), error);
+                BrctlEvictor.evict(path, timeout)
+            }
+        }
+    }
+}
+
+/// The parts of an `NSError` that tell failures apart.
+#[derive(Debug)]
+struct NsError {
+    domain: String,
+    code: isize,
+    description: String,
+}
+
+impl NsError {
+    fn internal(description: &str) -> Self {
+        Self {
+            domain: "evict-icloud".to_string(),
+            code: 0,
+            description: description.to_string(),
+        }
+    }
+
+    /// The message for errors with a `brctl` counterpart, worded so retries and the
+    /// summary treat them alike; `None` for the rest.
+    fn failure(&self) -> Option<&'static str> {
+        match (self.domain.as_str(), self.code) {
+            // NSFileNoSuchFileError and NSFileReadNoSuchFileError, or ENOENT
+            ("NSCocoaErrorDomain", 4 | 260) | ("NSPOSIXErrorDomain", 2) => Some("No such file or directory"),
+            // NSUbiquitousFileUnavailableError
+            ("NSCocoaErrorDomain", 4353) => Some("Not in iCloud"),
+            // NSUbiquitousFileNotUploadedDueToQuotaError
+            ("NSCocoaErrorDomain", 4354) => Some("Not uploaded, iCloud storage is full"),
+            // NSUbiquitousFileUbiquityServerNotAvailable
+            ("NSCocoaErrorDomain", 4355) => Some("iCloud server is not available"),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for NsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} {})", self.description, self.domain, self.code)
+    }
+}
+
+/// A selector or class name, which must end in a nul byte.
+fn name(bytes: &'static [u8]) -> *const c_char {
+    debug_assert_eq!(bytes.last(), Some(&0));
+    bytes.as_ptr().cast()
+}
+
+/// Send a message without arguments, e.g. a getter returning an object or an integer.
+///
+/// # Safety
+///
+/// `receiver` must be an object or class responding to `selector` with a result of type `R`.
+unsafe fn send<R>(receiver: Id, selector: &'static [u8]) -> R {
+    // The runtime needs objc_msgSend cast to the exact signature of the method
+    let send: unsafe extern "C" fn(Id, Sel) -> R = std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+    send(receiver, sel_registerName(name(selector)))
+}
+
+/// The contents of an `NSString`, or an empty string for `nil`.
+///
+/// # Safety
+///
+/// `string` must be `nil` or an `NSString`.
+unsafe fn string(string: Id) -> String {
+    if string.is_null() {
+        return String::new();
+    }
+    let utf8: *const c_char = send(string, b"UTF8String\0");
+    if utf8.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(utf8).to_string_lossy().into_owned()
+}
+
+/// Ask `NSFileManager` to remove the local copy of `path`.
+fn evict_ubiquitous_item(path: &Path) -> Result<(), NsError> {
+    let path_bytes =
+        CString::new(path.as_os_str().as_bytes()).map_err(|_| NsError::internal("path contains a nul byte"))?;
+    let is_directory = Bool::from(path.is_dir());
+
+    // SAFETY: every message below goes to an object of the class that declares it, cast to
+    // the method's signature, and the objects live until the pool is popped
+    unsafe {
+        let pool = objc_autoreleasePoolPush();
+        let result = (|| {
+            let file_manager_class = objc_getClass(name(b"NSFileManager\0"));
+            let url_class = objc_getClass(name(b"NSURL\0"));
+            if file_manager_class.is_null() || url_class.is_null() {
+                return Err(NsError::internal("Foundation is not available"));
+            }
+            let file_manager: Id = send(file_manager_class, b"defaultManager\0");
+
+            // Built from the raw bytes, so names that aren't valid UTF-8 survive
+            let file_url: unsafe extern "C" fn(Id, Sel, *const c_char, Bool, Id) -> Id =
+                std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            let url = file_url(
+                url_class,
+                sel_registerName(name(b"fileURLWithFileSystemRepresentation:isDirectory:relativeToURL:\0")),
+                path_bytes.as_ptr(),
+                is_directory,
+                std::ptr::null_mut(),
+            );
+            if url.is_null() {
+                return Err(NsError::internal("path can't be turned into a file URL"));
+            }
+
+            let evict: unsafe extern "C" fn(Id, Sel, Id, *mut Id) -> Bool =
+                std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            let mut error: Id = std::ptr::null_mut();
+            let evicted = evict(
+                file_manager,
+                sel_registerName(name(b"evictUbiquitousItemAtURL:error:\0")),
+                url,
+                &mut error,
+            );
+            if evicted != 0 {
+                return Ok(());
+            }
+            if error.is_null() {
+                return Err(NsError::internal("eviction failed without an error"));
+            }
+            Err(NsError {
+                domain: string(send(error, b"domain\0")),
+                code: send(error, b"code\0"),
+                description: string(send(error, b"localizedDescription\0")),
+            })
+        })();
+        objc_autoreleasePoolPop(pool);
+        result
+    }
+}
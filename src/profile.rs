@@ -0,0 +1,299 @@
+//! Saved option presets for `--profile`, read from a config file such as
+//!
+//! ```toml
+//! [profiles.photos]
+//! older-than = "90d"
+//! include = ["*.heic", "*.jpg"]
+//! dry-run = true
+//! ```
+//!
+//! Only the subset of TOML that options need is understood: `[profiles.<name>]` tables
+//! holding strings, numbers, booleans and single-line arrays of them.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgAction, Command};
+
+/// `$XDG_CONFIG_HOME/evict-icloud/config.toml`, or `~/.config/evict-icloud/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config.join("evict-icloud").join("config.toml"))
+}
+
+/// The name given to `--profile` in `args`, which are not parsed yet.
+pub fn requested(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--" => break,
+            "--profile" => return args.next().map(String::from),
+            arg => {
+                if let Some(name) = arg.strip_prefix("--profile=") {
+                    return Some(name.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The options saved as profile `name` in `path`, as they would be typed on the command
+/// line of `command`, leaving out those `given` says are set there already.
+pub fn load(path: &Path, name: &str, command: &Command, given: &dyn Fn(&str) -> bool) -> Result<Vec<OsString>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let mut found = false;
+    let mut current: Option<String> = None;
+    let mut args = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let at = |message: String| format!("{}:{}: {}", path.display(), index + 1, message);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(table) = line.strip_prefix('[') {
+            let table = table
+                .strip_suffix(']')
+                .ok_or_else(|| at(format!("unterminated table header '{}'", line)))?
+                .trim();
+            let profile = table
+                .strip_prefix("profiles.")
+                .map(|profile| unquote(profile.trim()))
+                .ok_or_else(|| at(format!("expected a [profiles.<name>] table, got [{}]", table)))?;
+            found |= profile == name;
+            current = Some(profile);
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| at(format!("expected `option = value`, got '{}'", line)))?;
+        let Some(profile) = &current else {
+            return Err(at("options must be inside a [profiles.<name>] table".to_string()));
+        };
+        let key = key.trim().replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(key.as_str())
+                    || arg.get_all_aliases().unwrap_or_default().contains(&key.as_str())
+            })
+            .ok_or_else(|| at(format!("unknown option '{}'", key)))?;
+        let values = parse_value(value.trim()).map_err(at)?;
+        // Options the command line sets, or rules out, are left to it
+        let overridden = given(arg.get_id().as_str())
+            || command
+                .get_arguments()
+                .filter(|other| given(other.get_id().as_str()))
+                .any(|other| conflicts(command, arg, other) || conflicts(command, other, arg));
+        if profile != name || overridden {
+            continue;
+        }
+
+        let flag = format!("--{}", key);
+        match (arg.get_action(), values.as_slice()) {
+            (ArgAction::SetTrue | ArgAction::SetFalse, [Value::Bool(true)]) => args.push(OsString::from(&flag)),
+            (ArgAction::SetTrue | ArgAction::SetFalse, [Value::Bool(false)]) => {}
+            (ArgAction::SetTrue | ArgAction::SetFalse, _) => {
+                return Err(at(format!("'{}' is a flag and takes true or false", key)));
+            }
+            (ArgAction::Count, [Value::Text(count)]) => {
+                let count: usize = count
+                    .parse()
+                    .map_err(|_| at(format!("'{}' takes a number of repetitions, got '{}'", key, count)))?;
+                for _ in 0..count {
+                    args.push(OsString::from(&flag));
+                }
+            }
+            _ => {
+                for value in values {
+                    match value {
+                        Value::Text(value) => args.push(OsString::from(format!("{}={}", flag, value))),
+                        Value::Bool(value) => args.push(OsString::from(format!("{}={}", flag, value))),
+                    }
+                }
+            }
+        }
+    }
+
+    if !found {
+        return Err(format!("no profile named '{}' in {}", name, path.display()));
+    }
+    Ok(args)
+}
+
+/// Whether `arg` declares a conflict with `other`.
+fn conflicts(command: &Command, arg: &Arg, other: &Arg) -> bool {
+    command
+        .get_arg_conflicts_with(arg)
+        .iter()
+        .any(|conflict| conflict.get_id() == other.get_id())
+}
+
+enum Value {
+    Bool(bool),
+    /// Strings and numbers alike, as clap parses them
+    Text(String),
+}
+
+/// A scalar, or the elements of a single-line array.
+fn parse_value(input: &str) -> Result<Vec<Value>, String> {
+    let Some(items) = input.strip_prefix('[') else {
+        return parse_scalar(input).map(|value| vec![value]);
+    };
+    let items = items
+        .strip_suffix(']')
+        .ok_or_else(|| format!("unterminated array '{}'", input))?;
+    split_items(items)?
+        .into_iter()
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| parse_scalar(item.trim()))
+        .collect()
+}
+
+fn parse_scalar(input: &str) -> Result<Value, String> {
+    match input {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ if input.starts_with('"') || input.starts_with('\'') => {
+            let quote = &input[..1];
+            if input.len() < 2 || !input.ends_with(quote) {
+                return Err(format!("unterminated string {}", input));
+            }
+            Ok(Value::Text(unquote(input)))
+        }
+        _ if input.parse::<f64>().is_ok() => Ok(Value::Text(input.to_string())),
+        _ => Err(format!("expected a quoted string, number or boolean, got '{}'", input)),
+    }
+}
+
+/// The contents of a `"basic"` or `'literal'` string; anything else is returned as is.
+fn unquote(input: &str) -> String {
+    if let Some(literal) = input.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        return literal.to_string();
+    }
+    let Some(basic) = input.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) else {
+        return input.to_string();
+    };
+    let mut text = String::with_capacity(basic.len());
+    let mut chars = basic.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some(escaped) => text.push(escaped),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+/// Split array items at the commas outside of strings.
+fn split_items(items: &str) -> Result<Vec<&str>, String> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in items.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(open), c) if c == open && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ',') => {
+                parts.push(&items[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated string in array [{}]", items));
+    }
+    parts.push(&items[start..]);
+    Ok(parts)
+}
+
+/// `line` without a trailing `#` comment, leaving `#` inside strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(open), c) if c == open && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..index],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// Profile `test` from a config file holding `text`, leaving out the `given` options.
+    fn load_text(test: &str, text: &str, given: &[&str]) -> Result<Vec<String>, String> {
+        let path = std::env::temp_dir().join(format!("evict-icloud-profile-{}-{}.toml", test, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let args = load(&path, "test", &crate::Cli::command(), &|id| given.contains(&id));
+        std::fs::remove_file(&path).unwrap();
+        args.map(|args| args.into_iter().map(|arg| arg.into_string().unwrap()).collect())
+    }
+
+    #[test]
+    fn comments_end_outside_of_strings() {
+        assert_eq!(strip_comment("include = \"#1.jpg\" # photos"), "include = \"#1.jpg\" ");
+        assert_eq!(strip_comment("include = '#1.jpg'"), "include = '#1.jpg'");
+        assert_eq!(strip_comment("include = \"a\\\"#b\""), "include = \"a\\\"#b\"");
+        assert_eq!(strip_comment("# all of it"), "");
+    }
+
+    #[test]
+    fn array_items_split_at_commas_outside_of_strings() {
+        assert_eq!(split_items("\"a,b\", 'c,d', \"e\"").unwrap(), ["\"a,b\"", " 'c,d'", " \"e\""]);
+        assert!(split_items("\"a, b").is_err());
+        let args = load_text("commas", "[profiles.test]\ninclude = [\"*.{jpg,heic}\", 'raw,old/**']\n", &[]).unwrap();
+        assert_eq!(args, ["--include=*.{jpg,heic}", "--include=raw,old/**"]);
+    }
+
+    #[test]
+    fn strings_are_unescaped() {
+        assert_eq!(unquote("\"a\\\"b\\\\c\\td\""), "a\"b\\c\td");
+        assert_eq!(unquote("'a\\tb'"), "a\\tb");
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = load_text("unknown", "[profiles.test]\nolder-than = \"90d\"\ncolour = \"never\"\n", &[]).unwrap_err();
+        assert!(err.ends_with(":3: unknown option 'colour'"), "{}", err);
+        let err = load_text("missing", "[profiles.other]\ndry-run = true\n", &[]).unwrap_err();
+        assert!(err.starts_with("no profile named 'test'"), "{}", err);
+    }
+
+    #[test]
+    fn command_line_overrides_profile() {
+        let text = "[profiles.test]\nolder_than = \"90d\"\nverbose = 2\ndry-run = true\n\n[profiles.other]\nfree = \"1G\"\n";
+        assert_eq!(load_text("all", text, &[]).unwrap(), ["--older-than=90d", "--verbose", "--verbose", "--dry-run"]);
+        // Given on the command line itself, and conflicting with it
+        assert_eq!(load_text("given", text, &["older_than", "quiet"]).unwrap(), ["--dry-run"]);
+    }
+}
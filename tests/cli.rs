@@ -0,0 +1,210 @@
+//! Runs the binary against a fake `brctl` put first on `PATH`.
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// A directory of its own for one test, holding a `target` tree with `a.txt` and
+/// `b.txt`, and a `bin/brctl` that runs `evict` as its `brctl evict` command after
+/// creating `bin/busy`.
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(test: &str, evict: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("evict-icloud-cli-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        std::fs::write(dir.join("target").join("a.txt"), "first").unwrap();
+        std::fs::write(dir.join("target").join("b.txt"), "second").unwrap();
+
+        let brctl = dir.join("bin").join("brctl");
+        // `brctl help` only probes that brctl can be started
+        let script = format!("#!/bin/sh\n[ \"$1\" = evict ] || exit 0\nshift\n: > \"$(dirname \"$0\")/busy\"\n{}\n", evict);
+        std::fs::write(&brctl, script).unwrap();
+        std::fs::set_permissions(&brctl, std::fs::Permissions::from_mode(0o755)).unwrap();
+        Fixture { dir }
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let path = format!("{}:{}", self.dir.join("bin").display(), std::env::var("PATH").unwrap_or_default());
+        let mut command = Command::new(env!("CARGO_BIN_EXE_evict-icloud"));
+        command
+            .env("PATH", path)
+            .env_remove("RUST_LOG")
+            .arg(self.dir.join("target"))
+            .args(["--allow-non-icloud", "--keep-recent", "0", "--yes"])
+            .args(args)
+            .stdin(Stdio::null());
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    /// Start a run and press Ctrl+C `presses` times once `brctl` is busy.
+    fn interrupt(&self, args: &[&str], presses: usize) -> Output {
+        let child = self.command(args).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+        let busy = self.dir.join("bin").join("busy");
+        for _ in 0..100 {
+            if busy.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        for _ in 0..presses {
+            thread::sleep(Duration::from_millis(200));
+            let status = Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+            assert!(status.success());
+        }
+        child.wait_with_output().unwrap()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn exits_0_when_every_file_is_evicted() {
+    let fixture = Fixture::new("exit-0", "exit 0");
+    let output = fixture.run(&[]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Files successful: 2"));
+}
+
+#[test]
+fn exits_1_when_a_file_fails() {
+    let fixture = Fixture::new(
+        "exit-1",
+        r#"for file; do case "$file" in *b.txt) echo "not a cloud file" >&2; exit 1;; esac; done"#,
+    );
+    let output = fixture.run(&[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Files failed: 1"));
+}
+
+#[test]
+fn exits_2_on_invalid_arguments() {
+    let fixture = Fixture::new("exit-2", "exit 0");
+    let output = fixture.run(&["--min-size", "lots"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+
+    let missing = fixture.dir.join("missing");
+    let output = fixture.run(&[missing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+}
+
+#[test]
+fn exits_130_when_interrupted() {
+    let fixture = Fixture::new("exit-130", "sleep 1");
+    let output = fixture.interrupt(&["--batch-size", "1", "--concurrency", "1"], 1);
+    assert_eq!(output.status.code(), Some(130), "{}", stderr(&output));
+    // The file in hand is finished, the other never attempted
+    assert!(stderr(&output).contains("Files successful: 1"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Files never attempted: 1"), "{}", stderr(&output));
+}
+
+#[test]
+fn rejects_max_size_below_min_size() {
+    let fixture = Fixture::new("size-range", "exit 0");
+    let output = fixture.run(&["--min-size", "1M", "--max-size", "1K"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    let expected = "--max-size (1.00 KiB) must not be smaller than --min-size (1.00 MiB)";
+    assert!(stderr(&output).contains(expected), "{}", stderr(&output));
+
+    let output = fixture.run(&["--min-size", "10XB"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(stderr(&output).contains("unknown size unit 'XB'"), "{}", stderr(&output));
+}
+
+#[test]
+fn watch_warns_about_finding_nothing_once() {
+    let fixture = Fixture::new("watch", "exit 0");
+    let child = fixture
+        .command(&["--watch", "--rescan-every", "200ms", "--min-size", "1G"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(1500));
+    let status = Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130), "{}", stderr(&output));
+    assert_eq!(stderr(&output).matches("No files left to evict").count(), 1, "{}", stderr(&output));
+}
+
+fn stdout_lines(output: &Output) -> Vec<String> {
+    let mut lines: Vec<String> = String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn stdout_holds_only_printed_paths() {
+    let fixture = Fixture::new(
+        "streams",
+        r#"for file; do case "$file" in *b.txt) echo "not a cloud file" >&2; exit 1;; esac; done"#,
+    );
+    let target = fixture.dir.join("target");
+    let path = |name: &str| target.join(name).display().to_string();
+
+    let output = fixture.run(&["--print-paths", "-v"]);
+    assert_eq!(stdout_lines(&output), [path("a.txt")]);
+    let messages = stderr(&output);
+    assert!(messages.contains("evicted content of"), "{}", messages);
+    assert!(messages.contains("Failed evicting"), "{}", messages);
+    assert!(messages.contains("=== Summary ==="), "{}", messages);
+
+    let output = fixture.run(&["--print-paths", "--dry-run"]);
+    assert_eq!(stdout_lines(&output), [path("a.txt"), path("b.txt")]);
+    assert!(stderr(&output).contains("[dry-run] Would evict"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("=== Summary ==="), "{}", stderr(&output));
+
+    let output = fixture.run(&["-v"]);
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn exits_131_and_kills_brctl_on_a_second_ctrl_c() {
+    let fixture = Fixture::new("exit-131", r#"echo $$ > "$(dirname "$0")/brctl.pid"; exec sleep 30"#);
+    let started = std::time::Instant::now();
+    let output = fixture.interrupt(&[], 2);
+    assert_eq!(output.status.code(), Some(131), "{}", stderr(&output));
+    assert!(started.elapsed() < Duration::from_secs(10), "waited for brctl");
+    assert!(stderr(&output).contains("force quitting"), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("=== Summary"), "{}", stderr(&output));
+
+    let pid = std::fs::read_to_string(fixture.dir.join("bin").join("brctl.pid")).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    // Killed but not yet reaped shows up as a zombie (`Z`)
+    let state = Command::new("ps").args(["-o", "stat=", "-p", pid.trim()]).output().unwrap();
+    let state = String::from_utf8_lossy(&state.stdout);
+    assert!(state.trim().is_empty() || state.starts_with('Z'), "brctl {} is still running: {}", pid.trim(), state);
+}
+
+#[test]
+fn recursive_evict_needs_the_protections_turned_off() {
+    let fixture = Fixture::new("recursive", r#"echo "$@" >> "$(dirname "$0")/calls""#);
+    let output = fixture.run(&["--recursive-evict"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(stderr(&output).contains("pass --no-ignore and --evict-open-files"), "{}", stderr(&output));
+    assert!(!fixture.dir.join("bin").join("calls").exists());
+
+    let output = fixture.run(&["--recursive-evict", "--no-ignore", "--evict-open-files"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let calls = std::fs::read_to_string(fixture.dir.join("bin").join("calls")).unwrap();
+    assert_eq!(calls.trim(), fixture.dir.join("target").display().to_string());
+}
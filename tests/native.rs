@@ -0,0 +1,59 @@
+//! Times the native backend against `brctl` on real iCloud Drive files.
+//!
+//! Files can only be evicted once they are uploaded, so instead of a fresh temporary tree
+//! this takes a folder of downloaded files inside iCloud Drive and evicts half of them
+//! each way (download them again with `brctl download` to rerun):
+//!
+//! ```text
+//! EVICT_ICLOUD_BENCH_DIR=~/Library/Mobile\ Documents/com~apple~CloudDocs/bench \
+//!     cargo test --release --features native --test native -- --ignored --nocapture
+//! ```
+#![cfg(all(feature = "native", target_os = "macos"))]
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use evict_icloud::{run_with, BrctlEvictor, EvictOptions, EvictReport, Evictor, NativeEvictor};
+
+fn evict(files: &[PathBuf], evictor: &dyn Evictor) -> (EvictReport, Duration) {
+    let options = EvictOptions {
+        file_list: Some(files.iter().enumerate().map(|(index, path)| (index + 1, path.clone())).collect()),
+        keep_recent: Duration::ZERO,
+        evict_open_files: true,
+        ..EvictOptions::default()
+    };
+    let started = Instant::now();
+    let report = run_with(&options, evictor, &());
+    (report, started.elapsed())
+}
+
+#[test]
+#[ignore = "evicts real iCloud files; set EVICT_ICLOUD_BENCH_DIR to a folder of downloaded ones"]
+fn native_against_brctl() {
+    let dir = std::env::var_os("EVICT_ICLOUD_BENCH_DIR").expect("EVICT_ICLOUD_BENCH_DIR is not set");
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    assert!(files.len() >= 2, "the folder needs at least two files");
+    let (native, brctl): (Vec<_>, Vec<_>) = files.into_iter().enumerate().partition(|(index, _)| index % 2 == 0);
+    let native: Vec<PathBuf> = native.into_iter().map(|(_, path)| path).collect();
+    let brctl: Vec<PathBuf> = brctl.into_iter().map(|(_, path)| path).collect();
+
+    let (native_report, native_time) = evict(&native, &NativeEvictor);
+    let (brctl_report, brctl_time) = evict(&brctl, &BrctlEvictor);
+    for (backend, report, time) in [("native", &native_report, native_time), ("brctl", &brctl_report, brctl_time)] {
+        eprintln!(
+            "{}: {} of {} files in {:.3}s ({:.0} files/s)",
+            backend,
+            report.successful.files,
+            report.attempted.files,
+            time.as_secs_f64(),
+            report.attempted.files as f64 / time.as_secs_f64()
+        );
+    }
+    assert_eq!(native_report.failed.files, 0, "{:?}", native_report.failures);
+    assert_eq!(brctl_report.failed.files, 0, "{:?}", brctl_report.failures);
+}